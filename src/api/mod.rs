@@ -2,12 +2,15 @@ use serde::Serialize;
 use crate::miner::Handle as MinerHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::orphan::OrphanPool;
+use crate::network::sync::SyncState;
 use crate::generator::TransactionGenerator;
 use crate::types::mempool::Mempool;  // Update the path
 use crate::types::block::Block;
 use crate::types::hash::H256;
 use crate::types::hash::Hashable;
 use crate::Blockchain;
+use ring::signature::Ed25519KeyPair;
 
 use log::info;
 use std::collections::HashMap;
@@ -26,7 +29,10 @@ pub struct Server {
     miner: MinerHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    tx_generator: Arc<Mutex<TransactionGenerator>>,
+    tx_generator: Option<TransactionGenerator>, // None without a key to send from
+    sync: Arc<Mutex<SyncState>>,
+    orphan_pool: Arc<Mutex<OrphanPool>>,
+    mempool: Arc<Mutex<Mempool>>,
 }
 
 #[derive(Serialize)]
@@ -35,6 +41,15 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct ReorgInfo {
+    old_tip: String,
+    new_tip: String,
+    fork_point: String,
+    disconnected: Vec<String>,
+    connected: Vec<String>,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -57,28 +72,35 @@ macro_rules! respond_json {
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>,  // Add this parameter
+        sync: &Arc<Mutex<SyncState>>,
+        orphan_pool: &Arc<Mutex<OrphanPool>>,
+        generator_key: Option<Ed25519KeyPair>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
-        let tx_generator = Arc::new(Mutex::new(
+        let tx_generator = generator_key.map(|keypair| {
             TransactionGenerator::new(
                 network.clone(), 
                 Arc::clone(mempool), 
                 Arc::clone(blockchain),
-                network.p2p_addr,
+                keypair,
             )
-        ));
+        });
         let server = Arc::new(Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
             tx_generator: tx_generator,
+            sync: Arc::clone(sync),
+            orphan_pool: Arc::clone(orphan_pool),
+            mempool: Arc::clone(mempool),
         });
         thread::spawn(move || {
             let server_clone = Arc::clone(&server);
@@ -145,20 +167,92 @@ impl Server {
                                 }
                             };
                             
-                            // Clone the generator outside the lock
-                            let generator = {
-                                let tx_generator = server_clone.tx_generator.lock().unwrap();
-                                tx_generator.clone()
-                            };  // Lock is dropped here
-                            
-                            // Start the generator after dropping the lock
-                            generator.start(theta);
-                            respond_result!(req, true, "transaction generator started");
+                            let generator = match server_clone.tx_generator.clone() {
+                                Some(generator) => generator,
+                                None => {
+                                    respond_result!(req, false, "no funded key for the transaction generator, give one with --generator-key");
+                                    return;
+                                }
+                            };
+                            match generator.start(theta) {
+                                Ok(()) => respond_result!(req, true, "transaction generator started"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
                         }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
+                        "/network/peers/configured" => {
+                            respond_json!(req, network.configured_peers());
+                        }
+                        "/network/bans" => {
+                            respond_json!(req, network.bans());
+                        }
+                        "/network/ban" | "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("ip") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing ip");
+                                    return;
+                                }
+                            };
+                            let ip = match ip.parse::<std::net::IpAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing ip: {}", e));
+                                    return;
+                                }
+                            };
+                            if url.path() == "/network/unban" {
+                                if network.unban(&ip) {
+                                    respond_result!(req, true, "ok");
+                                } else {
+                                    respond_result!(req, false, format!("{} is not banned", ip));
+                                }
+                                return;
+                            }
+                            // the configured ban time unless a duration in seconds is given
+                            let duration = match params.get("duration").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing duration: {}", e));
+                                    return;
+                                }
+                                None => None,
+                            };
+                            network.ban(ip, duration);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/sync" => {
+                            let (tip_height, best_header_height) = {
+                                let blockchain = blockchain.lock().unwrap();
+                                (blockchain.height(&blockchain.tip()).unwrap_or(0), blockchain.best_header_height())
+                            };
+                            let status = server_clone.sync.lock().unwrap().status(tip_height, best_header_height);
+                            respond_json!(req, status);
+                        }
+                        "/mempool/stats" => {
+                            let stats = {
+                                let mut mempool = server_clone.mempool.lock().unwrap();
+                                mempool.expire();
+                                mempool.stats()
+                            };
+                            respond_json!(req, stats);
+                        }
+                        "/network/orphans" => {
+                            let status = {
+                                let mut orphan_pool = server_clone.orphan_pool.lock().unwrap();
+                                orphan_pool.expire();
+                                orphan_pool.status()
+                            };
+                            respond_json!(req, status);
+                        }
                         "/blockchain/state" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -248,6 +342,23 @@ impl Server {
                             
                             respond_json!(req, result);
                         }
+                        "/blockchain/reorgs" => {
+                            let reorgs = blockchain.lock().unwrap().recent_reorgs();
+                            let to_strings = |v: &[H256]| -> Vec<String> {
+                                v.iter().map(|h| h.to_string()).collect()
+                            };
+                            let result: Vec<ReorgInfo> = reorgs
+                                .iter()
+                                .map(|r| ReorgInfo {
+                                    old_tip: r.old_tip.to_string(),
+                                    new_tip: r.new_tip.to_string(),
+                                    fork_point: r.fork_point.to_string(),
+                                    disconnected: to_strings(&r.disconnected),
+                                    connected: to_strings(&r.connected),
+                                })
+                                .collect();
+                            respond_json!(req, result);
+                        }
                         "/blockchain/longest-chain-tx-count" => {
                             // unimplemented!()
                             respond_result!(req, false, "unimplemented!");
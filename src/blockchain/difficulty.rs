@@ -0,0 +1,86 @@
+use crate::types::hash::H256;
+use crate::types::uint::U256;
+
+/// A single retarget never makes the target more than this many times easier or harder.
+pub const MAX_ADJUSTMENT_FACTOR: u128 = 4;
+
+/// Scale `target` by `actual / expected`, where both are the time spent on the retarget window,
+/// in milliseconds. The ratio is clamped to [1/MAX_ADJUSTMENT_FACTOR, MAX_ADJUSTMENT_FACTOR] and
+/// the result never exceeds the maximum target.
+pub fn retarget(target: &H256, actual: u128, expected: u128) -> H256 {
+    let expected = expected.max(1);
+    let actual = actual
+        .max(expected / MAX_ADJUSTMENT_FACTOR)
+        .min(expected * MAX_ADJUSTMENT_FACTOR)
+        .max(1);
+    // reduce the ratio so both terms fit into a u64
+    let divisor = gcd(actual, expected);
+    let mut actual = actual / divisor;
+    let mut expected = expected / divisor;
+    while actual > u64::MAX as u128 || expected > u64::MAX as u128 {
+        actual >>= 1;
+        expected >>= 1;
+    }
+    let expected = (expected as u64).max(1);
+    let actual = (actual as u64).max(1);
+
+    let target = U256::from(*target);
+    // divide first when the multiplication would overflow; the precision loss is negligible
+    let scaled = match target.checked_mul_u64(actual) {
+        Some(product) => product.div_u64(expected),
+        None => match target.div_u64(expected).checked_mul_u64(actual) {
+            Some(product) => product,
+            None => U256::max_value(),
+        },
+    };
+    scaled.into()
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+/// Expected number of hashes needed to find a block at `target`, i.e. 2^256 / (target + 1).
+pub fn work(target: &H256) -> U256 {
+    let target = U256::from(*target);
+    match target.checked_add(&U256::from_u64(1)) {
+        // 2^256 / (target + 1) == (2^256 - target - 1) / (target + 1) + 1
+        Some(divisor) => (!target).div(&divisor).saturating_add(&U256::from_u64(1)),
+        None => U256::from_u64(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamped_adjustment() {
+        let target: H256 = hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        // twice as slow as expected: the target doubles
+        assert_eq!(
+            retarget(&target, 2000, 1000),
+            hex!("0001fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe").into()
+        );
+        // a thousand times faster than expected: clamped to four times harder
+        assert_eq!(
+            retarget(&target, 1, 1000),
+            hex!("00003fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into()
+        );
+        // never easier than the maximum target
+        let easiest = H256::from([0xff; 32]);
+        assert_eq!(retarget(&easiest, 4000, 1000), easiest);
+    }
+
+    #[test]
+    fn work_from_target() {
+        assert_eq!(work(&H256::from([0xff; 32])), U256::from_u64(1));
+        let target: H256 = hex!("00007fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(work(&target), U256::from_u64(1 << 17));
+    }
+}
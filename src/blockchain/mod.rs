@@ -1,35 +1,194 @@
-use crate::types::block::Block;
+use crate::types::block::{compute_merkle_root, Block, Header};
 use crate::types::hash::H256;
 use crate::types::hash::Hashable;
 use crate::types::address::Address;
-use crate::types::state::State;  // Add this import
-use std::collections::HashMap;
+use crate::types::state::{State, TransactionError};  // Add this import
+use crate::types::transaction::SignedTransaction;
+use crate::types::uint::U256;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
 use hex_literal::hex;
 use ring::signature::Ed25519KeyPair;
 use ring::rand::SystemRandom;
 use ring::signature::KeyPair;
 use crate::info;
+use std::io;
+use std::path::Path;
 
+pub mod difficulty;
+pub mod spec;
+pub mod store;
+use store::BlockStore;
+
+/// Number of previous blocks whose median timestamp a new block must not precede
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far a block timestamp may be ahead of the local clock, in milliseconds
+pub const MAX_FUTURE_DRIFT: u128 = 2 * 60 * 60 * 1000;
+
+/// Reasons a block is rejected by `Blockchain::insert`
 #[derive(Debug)]
 #[derive(Clone)]  // Add this line
 pub enum BlockchainError {
-    BlockNotInserted,
-    InvalidNonce,
-    InsufficientBalance,
-    InvalidTransaction,  
-    StateError,
+    DuplicateBlock,
+    UnknownParent(H256),
+    InvalidProofOfWork,
+    WrongDifficulty { expected: H256, found: H256 },
+    MerkleRootMismatch { expected: H256, found: H256 },
+    TimestampTooOld { median: u128, found: u128 },
+    TimestampInFuture { limit: u128, found: u128 },
+    BlockTooLarge { limit: usize, found: usize },
+    InvalidTransaction { index: usize, error: TransactionError },
+    StorageError(String),
+}
+
+impl std::fmt::Display for BlockchainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockchainError::DuplicateBlock => write!(f, "block already known"),
+            BlockchainError::UnknownParent(parent) => write!(f, "unknown parent {}", parent),
+            BlockchainError::InvalidProofOfWork => write!(f, "block hash above its difficulty"),
+            BlockchainError::WrongDifficulty { expected, found } => {
+                write!(f, "difficulty {} does not match expected {}", found, expected)
+            }
+            BlockchainError::MerkleRootMismatch { expected, found } => {
+                write!(f, "merkle root {} does not match content root {}", found, expected)
+            }
+            BlockchainError::TimestampTooOld { median, found } => {
+                write!(f, "timestamp {} before median time {}", found, median)
+            }
+            BlockchainError::TimestampInFuture { limit, found } => {
+                write!(f, "timestamp {} too far in the future (limit {})", found, limit)
+            }
+            BlockchainError::BlockTooLarge { limit, found } => {
+                write!(f, "block holds {} transactions, limit {}", found, limit)
+            }
+            BlockchainError::InvalidTransaction { index, error } => {
+                write!(f, "transaction {} is invalid: {}", index, error)
+            }
+            BlockchainError::StorageError(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlockchainError {}
+
+impl BlockchainError {
+    /// Whether the block breaks a consensus rule, as opposed to arriving at the wrong time or
+    /// failing on our side. A peer relaying such a block is misbehaving.
+    pub fn is_invalid(&self) -> bool {
+        match self {
+            BlockchainError::DuplicateBlock
+            | BlockchainError::UnknownParent(_)
+            | BlockchainError::TimestampInFuture { .. } // may be our clock
+            | BlockchainError::StorageError(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Consensus parameters that nodes of the same network must agree on
+#[derive(Serialize, Debug, Clone)]
+pub struct ConsensusParams {
+    pub genesis_difficulty: H256,
+    pub genesis_timestamp: u128,
+    pub genesis_allocation: Vec<(Address, u64)>, // Initial balances, paid out by the genesis block
+    pub max_block_size: usize, // Maximum number of transactions in a block, besides the coinbase
+    pub retarget_interval: u64, // Number of blocks between difficulty adjustments
+    pub target_block_time: u128, // Desired time between blocks, in milliseconds
+    pub initial_subsidy: u64, // Coins created by each block before the first halving
+    pub halving_interval: u64, // Number of blocks between two halvings of the subsidy
+}
+
+impl ConsensusParams {
+    /// Hash of all the parameters. The genesis block commits to the genesis parameters only, so
+    /// peers compare this as well before following each other's chain.
+    pub fn digest(&self) -> H256 {
+        ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(self).expect("Failed to serialize ConsensusParams")).into()
+    }
+
+    /// Coins the coinbase of the block at `height` may create
+    pub fn block_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            0
+        } else {
+            self.initial_subsidy >> halvings
+        }
+    }
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self {
+            genesis_difficulty: H256::from(hex!(
+                "00007fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+            )),
+            genesis_timestamp: 0,
+            // the three ICO accounts get 10M coins each
+            genesis_allocation: ICO_SEEDS
+                .iter()
+                .map(|seed| {
+                    let keypair = Ed25519KeyPair::from_seed_unchecked(seed).unwrap();
+                    (Address::from_public_key_bytes(keypair.public_key().as_ref()), 10_000_000)
+                })
+                .collect(),
+            max_block_size: 100,
+            retarget_interval: 20,
+            target_block_time: 2000,
+            initial_subsidy: 50,
+            halving_interval: 10_000,
+        }
+    }
+}
+
+/// Number of reorganizations kept for inspection through the API
+const REORG_HISTORY: usize = 64;
+
+/// How the tip moved after a block was inserted
+#[derive(Debug, Clone)]
+pub struct TipChange {
+    pub old_tip: H256,
+    pub new_tip: H256,
+    pub fork_point: H256, // Last block shared by the old and the new chain
+    pub disconnected: Vec<H256>, // Blocks that left the main chain, from the old tip backwards
+    pub connected: Vec<H256>, // Blocks that joined the main chain, from the fork point forwards
+}
+
+impl TipChange {
+    /// Whether the tip switched to a competing branch instead of extending the main chain
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
 }
+
+#[cfg(any(test, test_utilities))]
+impl ConsensusParams {
+    /// Parameters with the easiest possible difficulty, so that any block satisfies PoW
+    pub fn for_test() -> Self {
+        Self {
+            genesis_difficulty: H256::from([0xff; 32]),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Blockchain {
     pub blocks: HashMap<H256, Block>,
     pub states: HashMap<H256, State>,  // Maps block hash to state after that block
     chain_lengths: HashMap<H256, usize>, // Track chain length for each block's hash
-    tip: H256, // Track the tip of the longest chain
+    total_work: HashMap<H256, U256>, // Track cumulative work of the chain ending at each block
+    tip: H256, // Track the tip of the heaviest chain
+    genesis: H256,
+    headers: HashMap<H256, (Header, usize)>, // Checked headers whose body has not arrived yet, with their height
+    store: Option<BlockStore>, // On-disk block log, if the node runs with a data directory
+    params: ConsensusParams,
+    reorgs: VecDeque<TipChange>, // Most recent reorganizations, oldest first
 }
 
 use lazy_static::lazy_static;
 
-// Define the three ICO keypairs as static variables
+// Define the three ICO keypairs as static variables, funded by the default chain spec
 
 lazy_static! {
     // Store the seeds instead of the keypairs
@@ -46,21 +205,40 @@ pub fn retrieve_keypair(p2p_addr: std::net::SocketAddr) -> Ed25519KeyPair {
     Ed25519KeyPair::from_seed_unchecked(&ICO_SEEDS[index]).unwrap()
 }
 
+/// The built-in key pair of `p2p_addr`, provided the genesis block of `params` funds it, as a
+/// custom chain spec may allocate its coins to other keys
+pub fn allocated_keypair(params: &ConsensusParams, p2p_addr: std::net::SocketAddr) -> Option<Ed25519KeyPair> {
+    let keypair = retrieve_keypair(p2p_addr);
+    let address = Address::from_public_key_bytes(keypair.public_key().as_ref());
+    params.genesis_allocation.iter()
+        .any(|(allocated, balance)| *allocated == address && *balance > 0)
+        .then_some(keypair)
+}
+
 impl Blockchain {
 
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_params(ConsensusParams::default())
+    }
+
+    /// Create a new blockchain with the given consensus parameters
+    pub fn with_params(params: ConsensusParams) -> Self {
+        assert!(params.retarget_interval >= 2, "retarget interval must be at least 2 blocks");
+        assert!(params.halving_interval >= 1, "halving interval must be at least 1 block");
         // Create a genesis block with fixed values
         let parent = H256::from([0u8; 32]); // Parent is all zeroes
         let nonce = 0;
-        let difficulty = H256::from(hex!(
-            "00007fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
-        ));
+        let difficulty = params.genesis_difficulty;
         
+        // The genesis block pays out the initial allocation, so that its hash commits to it
         let content = crate::types::block::Content {
-            data: vec![], // Empty transactions
+            data: params.genesis_allocation
+                .iter()
+                .map(|(address, balance)| SignedTransaction::coinbase(*address, *balance, 0))
+                .collect(),
         };
-        let timestamp = 0;
+        let timestamp = params.genesis_timestamp;
         let merkle_root = crate::types::block::compute_merkle_root(&content.data);
 
         let header = crate::types::block::Header {
@@ -78,67 +256,319 @@ impl Blockchain {
 
         let genesis_hash = genesis_block.hash();
 
+        // Initialize genesis state with the allocated balances
+        let mut genesis_state = State::new();
+        for tx in genesis_block.content.data.iter() {
+            genesis_state.process_coinbase(tx, 0, u64::MAX).expect("Genesis allocations are coinbases that fit in a balance");
+        }
+
         let mut blocks = HashMap::new();
         blocks.insert(genesis_hash, genesis_block);
 
-
-        // Initialize genesis state with all three ICO addresses
-        let mut genesis_state = State::new();
-        
-        // Create accounts for each ICO seed
-        for seed in ICO_SEEDS.iter() {
-            let keypair = Ed25519KeyPair::from_seed_unchecked(seed).unwrap();
-            let address = Address::from_public_key_bytes(keypair.public_key().as_ref());
-            genesis_state.create_account(address, 10_000_000); // 10M coins each
-}
         let mut states = HashMap::new();
         states.insert(genesis_hash, genesis_state);
 
         let mut chain_lengths = HashMap::new();
         chain_lengths.insert(genesis_hash, 0); // Genesis block has height 0
 
+        let mut total_work = HashMap::new();
+        total_work.insert(genesis_hash, difficulty::work(&difficulty));
+
         Self {
             blocks,
             states,
             chain_lengths,
+            total_work,
             tip: genesis_hash, // The genesis block is the initial tip
+            genesis: genesis_hash,
+            headers: HashMap::new(),
+            store: None,
+            params,
+            reorgs: VecDeque::new(),
         }
     }
 
-    /// Insert a block into the blockchain
-    pub fn insert(&mut self, block: &Block) -> Result<(), BlockchainError> {
+    /// Open a blockchain persisted under `data_dir`, replaying every stored block on top of the
+    /// genesis block. Blocks inserted afterwards are appended to the store. Every stored block
+    /// was valid when it was stored, so one that fails the replay means the directory holds
+    /// another chain, or one built under other consensus rules, and opening it fails.
+    pub fn open(data_dir: &Path, params: ConsensusParams) -> io::Result<Self> {
+        let mut blockchain = Self::with_params(params);
+        let mut store = BlockStore::open(data_dir)?;
+        for block in store.blocks()? {
+            blockchain.insert(&block).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("stored block {} fails replay: {}", block.hash(), e),
+                )
+            })?;
+        }
+        info!("Loaded {} blocks from {}", store.len(), data_dir.display());
+        blockchain.store = Some(store);
+        Ok(blockchain)
+    }
+
+    /// Validate a block against every consensus rule and insert it into the blockchain,
+    /// returning how the tip moved if it did
+    pub fn insert(&mut self, block: &Block) -> Result<Option<TipChange>, BlockchainError> {
         let block_hash = block.hash();
         let parent_hash = block.get_parent();
+        if self.blocks.contains_key(&block_hash) {
+            return Err(BlockchainError::DuplicateBlock);
+        }
     
         if let Some(parent_state) = self.states.get(&parent_hash) {
+            let parent_state = parent_state.clone();
             // Process transactions to get new state
-            let new_state = self.process_block_transactions(block, parent_state.clone())?;  // Add ? here
+            let new_state = self.validate_block(block)
+                .and_then(|_| self.process_block_transactions(block, parent_state))
+                .inspect_err(|_| self.discard_header(&block_hash))?;
+
+            // Persist the block before it becomes visible in memory
+            if let Some(store) = self.store.as_mut() {
+                store.append(block)
+                    .map_err(|e| BlockchainError::StorageError(e.to_string()))?;
+            }
             
             // Store block and its state
+            self.headers.remove(&block_hash);
             self.blocks.insert(block_hash, block.clone());
             self.states.insert(block_hash, new_state);  // Remove clone() since new_state is already owned
             
-            // Update chain length
+            // Update chain length and cumulative work
             let new_length = self.chain_lengths[&parent_hash] + 1;
             self.chain_lengths.insert(block_hash, new_length);
+            let new_work = self.total_work[&parent_hash]
+                .saturating_add(&difficulty::work(&block.get_difficulty()));
+            self.total_work.insert(block_hash, new_work);
             
-            // Update tip if new chain is longer
-            if new_length > self.chain_lengths[&self.tip] {
+            // Update tip if new chain holds more work, breaking ties by the smaller block hash
+            let tip_work = self.total_work[&self.tip];
+            if new_work > tip_work || (new_work == tip_work && block_hash < self.tip) {
+                let change = self.tip_change(self.tip, block_hash);
                 self.tip = block_hash;
+                if change.is_reorg() {
+                    info!(
+                        "Chain reorganization at fork point {:?}: disconnected {} blocks, connected {} blocks, new tip {:?}",
+                        change.fork_point, change.disconnected.len(), change.connected.len(), block_hash
+                    );
+                    if self.reorgs.len() == REORG_HISTORY {
+                        self.reorgs.pop_front();
+                    }
+                    self.reorgs.push_back(change.clone());
+                }
+                return Ok(Some(change));
             }
             
-            Ok(())
+            Ok(None)
         } else {
             info!("Block not inserted because parent state not found");
-            Err(BlockchainError::BlockNotInserted)
+            Err(BlockchainError::UnknownParent(parent_hash))
+        }
+    }
+
+    /// Check a block whose parent is known: its header, then that the content matches it
+    fn validate_block(&self, block: &Block) -> Result<(), BlockchainError> {
+        self.validate_header(&block.header)?;
+
+        let transactions = block.content.data.len().saturating_sub(1);
+        if transactions > self.params.max_block_size {
+            return Err(BlockchainError::BlockTooLarge { limit: self.params.max_block_size, found: transactions });
+        }
+
+        let merkle_root = compute_merkle_root(&block.content.data);
+        if block.header.merkle_root != merkle_root {
+            return Err(BlockchainError::MerkleRootMismatch {
+                expected: merkle_root,
+                found: block.header.merkle_root,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check a header whose parent header is known against the difficulty, proof-of-work and
+    /// timestamp rules
+    fn validate_header(&self, header: &Header) -> Result<(), BlockchainError> {
+        let parent_hash = header.parent;
+        let expected = self.next_difficulty(&parent_hash)
+            .ok_or(BlockchainError::UnknownParent(parent_hash))?;
+        if header.difficulty != expected {
+            return Err(BlockchainError::WrongDifficulty { expected, found: header.difficulty });
+        }
+        if header.hash() > header.difficulty {
+            return Err(BlockchainError::InvalidProofOfWork);
+        }
+
+        let median = self.median_time_past(&parent_hash);
+        if header.timestamp < median {
+            return Err(BlockchainError::TimestampTooOld { median, found: header.timestamp });
         }
+        let limit = now_millis() + MAX_FUTURE_DRIFT;
+        if header.timestamp > limit {
+            return Err(BlockchainError::TimestampInFuture { limit, found: header.timestamp });
+        }
+        Ok(())
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` headers ending at `hash`
+    fn median_time_past(&self, hash: &H256) -> u128 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = self.header(hash);
+        while let Some((header, _)) = current {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            timestamps.push(header.timestamp);
+            current = self.header(&header.parent);
+        }
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Look up a header and its height, whether we have the whole block or only the header
+    fn header(&self, hash: &H256) -> Option<(&Header, usize)> {
+        match self.blocks.get(hash) {
+            Some(block) => Some((&block.header, self.chain_lengths[hash])),
+            None => self.headers.get(hash).map(|(header, height)| (header, *height)),
+        }
+    }
+
+    /// Whether the header of a block is known, with or without its body
+    pub fn has_header(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash) || self.headers.contains_key(hash)
+    }
+
+    /// Check a header ahead of its block body and remember it, so that the headers after it can
+    /// be checked too. Returns whether the header was new.
+    pub fn insert_header(&mut self, header: &Header) -> Result<bool, BlockchainError> {
+        let hash = header.hash();
+        if self.has_header(&hash) {
+            return Ok(false);
+        }
+        self.validate_header(header)?;
+        let (_, parent_height) = self.header(&header.parent)
+            .ok_or(BlockchainError::UnknownParent(header.parent))?;
+        self.headers.insert(hash, (header.clone(), parent_height + 1));
+        Ok(true)
+    }
+
+    /// Forget a header whose block turned out to be invalid, along with the headers built on it
+    fn discard_header(&mut self, hash: &H256) {
+        let mut discarded = vec![*hash];
+        while let Some(hash) = discarded.pop() {
+            if self.headers.remove(&hash).is_some() {
+                discarded.extend(
+                    self.headers.iter()
+                        .filter(|(_, (header, _))| header.parent == hash)
+                        .map(|(child, _)| *child)
+                );
+            }
+        }
+    }
+
+    /// Height of the highest known header, with or without its body
+    pub fn best_header_height(&self) -> usize {
+        self.headers.values()
+            .map(|(_, height)| *height)
+            .max()
+            .unwrap_or(0)
+            .max(self.chain_lengths[&self.tip])
+    }
+
+    /// Hashes describing our main chain to a peer, from the tip backwards: the last ten blocks,
+    /// then exponentially sparser, always ending with the genesis block
+    pub fn block_locator(&self) -> Vec<H256> {
+        let chain = self.all_blocks_in_longest_chain();
+        let mut locator = Vec::new();
+        let mut index = chain.len() - 1;
+        let mut step = 1;
+        while index > 0 {
+            locator.push(chain[index]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator.push(chain[0]);
+        locator
+    }
+
+    /// Headers of our main chain following the first locator hash that is on it (or the genesis
+    /// block if none is), at most `max` of them
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let chain = self.all_blocks_in_longest_chain();
+        let positions: HashMap<H256, usize> = chain.iter()
+            .enumerate()
+            .map(|(position, hash)| (*hash, position))
+            .collect();
+        let start = locator.iter()
+            .find_map(|hash| positions.get(hash))
+            .copied()
+            .unwrap_or(0);
+        chain[start + 1..]
+            .iter()
+            .take(max)
+            .map(|hash| self.blocks[hash].header.clone())
+            .collect()
     }
 
-    /// Get the last block's hash of the longest chain
+    /// Smallest timestamp a child of `parent` may carry
+    pub fn min_timestamp(&self, parent: &H256) -> u128 {
+        self.median_time_past(parent)
+    }
+
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis
+    }
+
+    pub fn params(&self) -> &ConsensusParams {
+        &self.params
+    }
+
+    /// Get the difficulty that a child of `parent` must carry.
+    ///
+    /// The difficulty stays the same as the parent's, except at heights that are a multiple of
+    /// the retarget interval: there it is recomputed from the header timestamps of the last
+    /// `retarget_interval` blocks. The first window, which would start at the genesis block and its
+    /// fixed timestamp, is skipped.
+    pub fn next_difficulty(&self, parent: &H256) -> Option<H256> {
+        let (parent_header, parent_height) = self.header(parent)?;
+        let interval = self.params.retarget_interval;
+        let height = parent_height as u64 + 1;
+        if !height.is_multiple_of(interval) || height == interval {
+            return Some(parent_header.difficulty);
+        }
+
+        // walk back to the first block of the window, at height `height - interval`
+        let mut first = parent_header;
+        for _ in 0..interval - 1 {
+            first = self.header(&first.parent)?.0;
+        }
+        let actual = parent_header.timestamp.saturating_sub(first.timestamp);
+        let expected = (interval - 1) as u128 * self.params.target_block_time;
+        let difficulty = difficulty::retarget(&parent_header.difficulty, actual, expected);
+        log::debug!(
+            "Retargeting at height {}: window took {} ms (expected {} ms), new difficulty {}",
+            height, actual, expected, difficulty
+        );
+        Some(difficulty)
+    }
+
+    /// Get the last block's hash of the heaviest chain
     pub fn tip(&self) -> H256 {
         self.tip
     }
 
+    /// Get the height of a block, the genesis block being at height 0
+    pub fn height(&self, hash: &H256) -> Option<usize> {
+        self.chain_lengths.get(hash).copied()
+    }
+
+    /// Get the cumulative work of the chain ending at a block
+    pub fn total_work(&self, hash: &H256) -> Option<U256> {
+        self.total_work.get(hash).copied()
+    }
+
     pub fn get_current_state(&self) -> State {
         self.states.get(&self.tip)
             .expect("Tip state must exist")
@@ -150,7 +580,42 @@ impl Blockchain {
     }
 
 
-    /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
+    /// Get the most recent chain reorganizations, oldest first
+    pub fn recent_reorgs(&self) -> Vec<TipChange> {
+        self.reorgs.iter().cloned().collect()
+    }
+
+    /// Walk back from both tips to their common ancestor
+    fn tip_change(&self, old_tip: H256, new_tip: H256) -> TipChange {
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        let mut old = old_tip;
+        let mut new = new_tip;
+        while self.chain_lengths[&old] > self.chain_lengths[&new] {
+            disconnected.push(old);
+            old = self.blocks[&old].get_parent();
+        }
+        while self.chain_lengths[&new] > self.chain_lengths[&old] {
+            connected.push(new);
+            new = self.blocks[&new].get_parent();
+        }
+        while old != new {
+            disconnected.push(old);
+            old = self.blocks[&old].get_parent();
+            connected.push(new);
+            new = self.blocks[&new].get_parent();
+        }
+        connected.reverse();
+        TipChange {
+            old_tip,
+            new_tip,
+            fork_point: old,
+            disconnected,
+            connected,
+        }
+    }
+
+    /// Get all blocks' hashes of the heaviest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut chain = Vec::new();
         let mut current_hash = self.tip;
@@ -165,20 +630,41 @@ impl Blockchain {
         chain
     }
 
-    fn process_block_transactions(&mut self, block: &Block, parent_state: State) -> Result<State, BlockchainError> {
+    fn process_block_transactions(&self, block: &Block, parent_state: State) -> Result<State, BlockchainError> {
+        let height = self.chain_lengths[&block.get_parent()] as u64 + 1;
+        self.state_after(block, height, parent_state)
+    }
+
+    /// The state once the transactions of `block`, at `height`, apply on `parent_state`. The
+    /// blockchain need not have its parent, e.g. for a block the miner builds on its own.
+    pub fn state_after(&self, block: &Block, height: u64, parent_state: State) -> Result<State, BlockchainError> {
         let mut new_state = parent_state;
+
+        // The first transaction must be the coinbase, paying at most the block subsidy
+        let coinbase = block.content.data.first().ok_or(BlockchainError::InvalidTransaction {
+            index: 0,
+            error: TransactionError::MissingCoinbase,
+        })?;
+        new_state.process_coinbase(coinbase, height, self.params.block_subsidy(height))
+            .map_err(|error| BlockchainError::InvalidTransaction { index: 0, error })?;
         
-        // Process each transaction, returning error if any fail
-        for tx in &block.content.data {
-            if let Err(_) = new_state.process_transaction(tx) {
-                info!("");
-            }
+        // Process each transaction in order, returning error if any fail. Fees go to the coinbase receiver
+        for (index, tx) in block.content.data.iter().enumerate().skip(1) {
+            new_state.process_transaction(tx, &coinbase.transaction.receiver)
+                .map_err(|error| BlockchainError::InvalidTransaction { index, error })?;
         }
         
         Ok(new_state)
     }
 }
 
+fn now_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
 impl Default for Blockchain {
     fn default() -> Self {
         Self::new()  // Remove p2p_addr parameter
@@ -190,18 +676,332 @@ impl Default for Blockchain {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::block::generate_random_block;
+    use crate::types::block::{generate_random_block, generate_random_block_at};
     use crate::types::hash::Hashable;
+    use crate::types::transaction::Transaction;
 
     #[test]
     fn insert_one() {
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::with_params(ConsensusParams::for_test());
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
         blockchain.insert(&block);
         assert_eq!(blockchain.tip(), block.hash());
 
     }
+
+    #[test]
+    fn headers_first() {
+        let params = ConsensusParams {
+            genesis_difficulty: hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            ..Default::default()
+        };
+        let mut source = Blockchain::with_params(params.clone());
+        let mut blocks = Vec::new();
+        for i in 1..=30 {
+            let block = child_block(&source, &source.tip(), i * 1000);
+            source.insert(&block).unwrap();
+            blocks.push(block);
+        }
+        let locator = source.block_locator();
+        assert_eq!(locator[0], source.tip());
+        assert_eq!(*locator.last().unwrap(), source.genesis_hash());
+        assert!(locator.len() > 10 && locator.len() < 20);
+
+        let mut syncing = Blockchain::with_params(params);
+        let headers = source.headers_after(&syncing.block_locator(), 20);
+        assert_eq!(headers.len(), 20);
+        let mut tampered = headers[0].clone();
+        tampered.difficulty = H256::from([0xff; 32]);
+        assert!(matches!(syncing.insert_header(&tampered), Err(BlockchainError::WrongDifficulty { .. })));
+        assert!(matches!(syncing.insert_header(&headers[1]), Err(BlockchainError::UnknownParent(_))));
+        for header in headers.iter() {
+            assert!(syncing.insert_header(header).unwrap());
+        }
+        assert_eq!(syncing.best_header_height(), 20);
+        assert_eq!(syncing.tip(), syncing.genesis_hash());
+
+        // the next request continues after the last header
+        let rest = source.headers_after(&[headers[19].hash(), source.genesis_hash()], 100);
+        assert_eq!(rest.len(), 10);
+        assert_eq!(rest[0].parent, headers[19].hash());
+
+        for block in blocks[..20].iter() {
+            syncing.insert(block).unwrap();
+        }
+        assert_eq!(syncing.tip(), blocks[19].hash());
+        assert_eq!(syncing.best_header_height(), 20);
+    }
+
+    #[test]
+    fn reload_from_data_dir() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-chain-{}", rand::random::<u64>()));
+        let mut blockchain = Blockchain::open(&dir, ConsensusParams::for_test()).unwrap();
+        let block_1 = generate_random_block(&blockchain.tip());
+        let block_2 = generate_random_block_at(&block_1.hash(), 2);
+        let fork = generate_random_block(&blockchain.tip());
+        blockchain.insert(&block_1).unwrap();
+        blockchain.insert(&block_2).unwrap();
+        blockchain.insert(&fork).unwrap();
+        drop(blockchain);
+
+        let blockchain = Blockchain::open(&dir, ConsensusParams::for_test()).unwrap();
+        assert_eq!(blockchain.tip(), block_2.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 3);
+        assert!(blockchain.get_block(&fork.hash()).is_some());
+        assert!(blockchain.states.contains_key(&fork.hash()));
+        drop(blockchain);
+
+        // under another genesis block, the stored blocks do not connect
+        let mut params = ConsensusParams::for_test();
+        params.genesis_timestamp += 1;
+        assert!(Blockchain::open(&dir, params).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn child_block(blockchain: &Blockchain, parent: &H256, timestamp: u128) -> Block {
+        let height = blockchain.height(parent).unwrap() as u32 + 1;
+        let mut block = generate_random_block_at(parent, height);
+        block.header.timestamp = timestamp;
+        block.header.difficulty = blockchain.next_difficulty(parent).unwrap();
+        mine(block)
+    }
+
+    /// Grind the nonce until the block satisfies its difficulty
+    fn mine(mut block: Block) -> Block {
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        block
+    }
+
+    #[test]
+    fn retarget_every_interval() {
+        let params = ConsensusParams {
+            genesis_difficulty: hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            retarget_interval: 4,
+            target_block_time: 1000,
+            ..Default::default()
+        };
+        let genesis_difficulty = params.genesis_difficulty;
+        let mut blockchain = Blockchain::with_params(params);
+        let mut timestamp = 0;
+        for height in 1..8 {
+            timestamp += 250;
+            let block = child_block(&blockchain, &blockchain.tip(), timestamp);
+            // the first window starts at genesis and does not retarget
+            assert_eq!(block.get_difficulty(), genesis_difficulty, "height {}", height);
+            blockchain.insert(&block).unwrap();
+        }
+        // blocks 4..7 came four times faster than the target, so the target gets four times harder
+        let block = child_block(&blockchain, &blockchain.tip(), timestamp + 250);
+        assert_eq!(
+            block.get_difficulty(),
+            hex!("03ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into()
+        );
+    }
+
+    #[test]
+    fn heaviest_chain_wins() {
+        let params = ConsensusParams {
+            genesis_difficulty: hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            retarget_interval: 2,
+            target_block_time: 1000,
+            ..Default::default()
+        };
+        let mut blockchain = Blockchain::with_params(params);
+        let block_1 = child_block(&blockchain, &blockchain.tip(), 1000);
+        blockchain.insert(&block_1).unwrap();
+        let block_2 = child_block(&blockchain, &block_1.hash(), 2000);
+        blockchain.insert(&block_2).unwrap();
+
+        // a slow branch retargets to an easier difficulty at height 4
+        let slow_3 = child_block(&blockchain, &block_2.hash(), 6000);
+        blockchain.insert(&slow_3).unwrap();
+        let slow_4 = child_block(&blockchain, &slow_3.hash(), 7000);
+        blockchain.insert(&slow_4).unwrap();
+        // a fast branch retargets to a harder difficulty at height 4
+        let fast_3 = child_block(&blockchain, &block_2.hash(), 2001);
+        blockchain.insert(&fast_3).unwrap();
+        let fast_4 = child_block(&blockchain, &fast_3.hash(), 2002);
+        blockchain.insert(&fast_4).unwrap();
+        assert!(blockchain.total_work(&fast_4.hash()) > blockchain.total_work(&slow_4.hash()));
+        assert_eq!(blockchain.tip(), fast_4.hash());
+
+        // a longer chain of easy blocks does not beat a shorter chain with more work
+        let slow_5 = child_block(&blockchain, &slow_4.hash(), 8000);
+        blockchain.insert(&slow_5).unwrap();
+        assert_eq!(blockchain.tip(), fast_4.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain().last(), Some(&fast_4.hash()));
+    }
+
+    #[test]
+    fn equal_work_tie_break() {
+        let mut blockchain = Blockchain::with_params(ConsensusParams::for_test());
+        let genesis_hash = blockchain.tip();
+        let block_a = child_block(&blockchain, &genesis_hash, 1000);
+        let block_b = child_block(&blockchain, &genesis_hash, 1000);
+        blockchain.insert(&block_a).unwrap();
+        blockchain.insert(&block_b).unwrap();
+        assert_eq!(blockchain.tip(), std::cmp::min(block_a.hash(), block_b.hash()));
+    }
+
+    #[test]
+    fn report_reorg() {
+        let mut blockchain = Blockchain::with_params(ConsensusParams::for_test());
+        let genesis_hash = blockchain.tip();
+        let block_1 = child_block(&blockchain, &genesis_hash, 1000);
+        let change = blockchain.insert(&block_1).unwrap().unwrap();
+        assert!(!change.is_reorg());
+        assert_eq!(change.connected, vec![block_1.hash()]);
+
+        // depending on the tie-break, the reorg happens at the first or the second fork block
+        let fork_1 = child_block(&blockchain, &genesis_hash, 1000);
+        blockchain.insert(&fork_1).unwrap();
+        let fork_2 = child_block(&blockchain, &fork_1.hash(), 2000);
+        let change = blockchain.insert(&fork_2).unwrap().unwrap();
+        assert_eq!(change.new_tip, fork_2.hash());
+        assert_eq!(blockchain.tip(), fork_2.hash());
+
+        let reorgs = blockchain.recent_reorgs();
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(reorgs[0].fork_point, genesis_hash);
+        assert_eq!(reorgs[0].disconnected, vec![block_1.hash()]);
+        assert_eq!(reorgs[0].connected[0], fork_1.hash());
+    }
+
+    #[test]
+    fn reject_invalid_blocks() {
+        let mut params = ConsensusParams {
+            genesis_difficulty: hex!("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            ..Default::default()
+        };
+        let rich = Address::from([5u8; 20]);
+        params.genesis_allocation.push((rich, u64::MAX));
+        let mut blockchain = Blockchain::with_params(params);
+        let genesis_hash = blockchain.tip();
+        let valid = child_block(&blockchain, &genesis_hash, 1000);
+
+        let mut block = valid.clone();
+        block.header.parent = H256::from([1u8; 32]);
+        assert!(matches!(blockchain.insert(&block), Err(BlockchainError::UnknownParent(_))));
+
+        let mut block = valid.clone();
+        block.header.difficulty = H256::from([0xff; 32]);
+        assert!(matches!(blockchain.insert(&block), Err(BlockchainError::WrongDifficulty { .. })));
+
+        let mut block = valid.clone();
+        while block.hash() <= block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        assert!(matches!(blockchain.insert(&block), Err(BlockchainError::InvalidProofOfWork)));
+
+        let mut block = valid.clone();
+        block.content.data.push(Default::default());
+        let block = mine(block);
+        assert!(matches!(blockchain.insert(&block), Err(BlockchainError::MerkleRootMismatch { .. })));
+
+        // a second coinbase after the first one
+        let mut block = valid.clone();
+        block.content.data.push(Default::default());
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        let block = mine(block);
+        assert!(matches!(
+            blockchain.insert(&block),
+            Err(BlockchainError::InvalidTransaction { index: 1, error: TransactionError::UnexpectedCoinbase })
+        ));
+
+        let mut block = valid.clone();
+        block.content.data.clear();
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        let block = mine(block);
+        assert!(matches!(
+            blockchain.insert(&block),
+            Err(BlockchainError::InvalidTransaction { index: 0, error: TransactionError::MissingCoinbase })
+        ));
+
+        let mut block = valid.clone();
+        block.content.data[0] = SignedTransaction::coinbase(Address::default(), 51, 1);
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        let block = mine(block);
+        assert!(matches!(
+            blockchain.insert(&block),
+            Err(BlockchainError::InvalidTransaction { index: 0, error: TransactionError::ExcessiveReward { allowed: 50, found: 51 } })
+        ));
+
+        // the coinbase of another height
+        let mut block = valid.clone();
+        block.content.data[0] = SignedTransaction::coinbase(Address::default(), 50, 2);
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        let block = mine(block);
+        assert!(matches!(
+            blockchain.insert(&block),
+            Err(BlockchainError::InvalidTransaction { index: 0, error: TransactionError::WrongCoinbaseHeight { expected: 1, found: 2 } })
+        ));
+
+        // a reward or a fee that overflows the balance of its receiver
+        let mut block = valid.clone();
+        block.content.data[0] = SignedTransaction::coinbase(rich, 50, 1);
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        let block = mine(block);
+        assert!(matches!(
+            blockchain.insert(&block),
+            Err(BlockchainError::InvalidTransaction { index: 0, error: TransactionError::BalanceOverflow { balance: u64::MAX, credit: 50 } })
+        ));
+        let key = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let transfer = SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value: 10, nonce: 1, fee: 1 },
+            &key,
+        );
+        let mut block = valid.clone();
+        block.content.data[0] = SignedTransaction::coinbase(rich, 0, 1);
+        block.content.data.push(transfer.clone());
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        let block = mine(block);
+        assert!(matches!(
+            blockchain.insert(&block),
+            Err(BlockchainError::InvalidTransaction { index: 1, error: TransactionError::BalanceOverflow { .. } })
+        ));
+        // and leaves the state as it was
+        let mut state = blockchain.get_current_state().clone();
+        assert!(state.process_transaction(&transfer, &rich).is_err());
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        assert_eq!(state.get_account_state(&sender), blockchain.get_current_state().get_account_state(&sender));
+        assert!(state.get_account_state(&Address::from([7u8; 20])).is_none());
+
+        let block = child_block(&blockchain, &genesis_hash, now_millis() + 2 * MAX_FUTURE_DRIFT);
+        assert!(matches!(blockchain.insert(&block), Err(BlockchainError::TimestampInFuture { .. })));
+
+        assert!(blockchain.insert(&valid).is_ok());
+        assert!(matches!(blockchain.insert(&valid), Err(BlockchainError::DuplicateBlock)));
+        assert_eq!(blockchain.tip(), valid.hash());
+
+        // the median of the genesis block and `valid` is the timestamp of `valid`
+        let block = child_block(&blockchain, &valid.hash(), 500);
+        assert!(matches!(blockchain.insert(&block), Err(BlockchainError::TimestampTooOld { .. })));
+    }
+
+    #[test]
+    fn subsidy_halving() {
+        let params = ConsensusParams {
+            initial_subsidy: 50,
+            halving_interval: 100,
+            ..ConsensusParams::for_test()
+        };
+        assert_eq!(params.block_subsidy(1), 50);
+        assert_eq!(params.block_subsidy(99), 50);
+        assert_eq!(params.block_subsidy(100), 25);
+        assert_eq!(params.block_subsidy(250), 12);
+        assert_eq!(params.block_subsidy(100 * 64), 0);
+
+        let mut blockchain = Blockchain::with_params(params);
+        let mut block = generate_random_block(&blockchain.tip());
+        let miner = Address::from([9u8; 20]);
+        block.content.data[0] = SignedTransaction::coinbase(miner, 50, 1);
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        blockchain.insert(&block).unwrap();
+        assert_eq!(blockchain.get_current_state().get_account_state(&miner).unwrap().balance, 50);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
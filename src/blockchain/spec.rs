@@ -0,0 +1,149 @@
+use crate::types::address::Address;
+use crate::types::hash::H256;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use super::ConsensusParams;
+
+/// A network definition loaded from a JSON file, e.g.
+///
+/// ```json
+/// {
+///     "name": "testnet",
+///     "genesis_difficulty": "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+///     "genesis_timestamp": 0,
+///     "max_block_size": 100,
+///     "allocations": [
+///         { "address": "5f6ae2...", "balance": 10000000 }
+///     ]
+/// }
+/// ```
+///
+/// `retarget_interval`, `target_block_time`, `initial_subsidy` and `halving_interval` may be
+/// given as well, and fall back to the defaults otherwise.
+#[derive(Debug, Clone)]
+pub struct ChainSpec {
+    pub name: String,
+    pub params: ConsensusParams,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSpec {
+    name: String,
+    genesis_difficulty: String,
+    #[serde(default)]
+    genesis_timestamp: u128,
+    max_block_size: Option<usize>,
+    retarget_interval: Option<u64>,
+    target_block_time: Option<u128>,
+    initial_subsidy: Option<u64>,
+    halving_interval: Option<u64>,
+    allocations: Vec<RawAllocation>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAllocation {
+    address: String,
+    balance: u64,
+}
+
+impl ChainSpec {
+    /// Read and check a chain spec file
+    pub fn load(path: &Path) -> io::Result<ChainSpec> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_json(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn from_json(json: &str) -> Result<ChainSpec, String> {
+        let raw: RawSpec = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let defaults = ConsensusParams::default();
+        let params = ConsensusParams {
+            genesis_difficulty: raw.genesis_difficulty.parse::<H256>()?,
+            genesis_timestamp: raw.genesis_timestamp,
+            genesis_allocation: raw.allocations
+                .iter()
+                .map(|a| Ok((a.address.parse::<Address>()?, a.balance)))
+                .collect::<Result<Vec<_>, String>>()?,
+            max_block_size: raw.max_block_size.unwrap_or(defaults.max_block_size),
+            retarget_interval: raw.retarget_interval.unwrap_or(defaults.retarget_interval),
+            target_block_time: raw.target_block_time.unwrap_or(defaults.target_block_time),
+            initial_subsidy: raw.initial_subsidy.unwrap_or(defaults.initial_subsidy),
+            halving_interval: raw.halving_interval.unwrap_or(defaults.halving_interval),
+        };
+        if params.retarget_interval < 2 {
+            return Err("retarget interval must be at least 2 blocks".to_string());
+        }
+        if params.halving_interval < 1 {
+            return Err("halving interval must be at least 1 block".to_string());
+        }
+        // an address may be listed more than once, its balance is the sum
+        let mut balances: HashMap<Address, u64> = HashMap::new();
+        for (address, balance) in params.genesis_allocation.iter() {
+            let total = balances.entry(*address).or_default();
+            *total = total.checked_add(*balance)
+                .ok_or(format!("allocations to {} overflow its balance", address))?;
+        }
+        Ok(ChainSpec { name: raw.name, params })
+    }
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        ChainSpec {
+            name: "default".to_string(),
+            params: ConsensusParams::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{allocated_keypair, Blockchain};
+
+    #[test]
+    fn load_custom_network() {
+        let json = r#"{
+            "name": "testnet",
+            "genesis_difficulty": "0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "genesis_timestamp": 1700000000000,
+            "max_block_size": 10,
+            "allocations": [
+                { "address": "0101010101010101010101010101010101010101", "balance": 42 }
+            ]
+        }"#;
+        let spec = ChainSpec::from_json(json).unwrap();
+        assert_eq!(spec.name, "testnet");
+        assert_eq!(spec.params.max_block_size, 10);
+        assert_eq!(spec.params.retarget_interval, ConsensusParams::default().retarget_interval);
+
+        let blockchain = Blockchain::with_params(spec.params.clone());
+        let genesis_state = &blockchain.states[&blockchain.genesis_hash()];
+        assert_eq!(genesis_state.accounts.len(), 1);
+        assert_eq!(genesis_state.get_account_state(&Address::from([1u8; 20])).unwrap().balance, 42);
+        // a different genesis makes a different network
+        assert_ne!(blockchain.genesis_hash(), Blockchain::new().genesis_hash());
+        // and so do other rules on the same genesis block
+        let mut slower = spec.params.clone();
+        slower.target_block_time *= 2;
+        assert_eq!(Blockchain::with_params(slower.clone()).genesis_hash(), blockchain.genesis_hash());
+        assert_ne!(slower.digest(), spec.params.digest());
+        // the built-in keys have no coins on this network
+        let p2p_addr = "127.0.0.1:6000".parse().unwrap();
+        assert!(allocated_keypair(&spec.params, p2p_addr).is_none());
+        assert!(allocated_keypair(&ConsensusParams::default(), p2p_addr).is_some());
+
+        assert!(ChainSpec::from_json(&json.replace("0101", "01")).is_err());
+        assert!(ChainSpec::from_json(&json.replace("\"name\"", "\"nmae\"")).is_err());
+        let overflowing = json.replace(
+            r#"{ "address": "0101010101010101010101010101010101010101", "balance": 42 }"#,
+            r#"{ "address": "0101010101010101010101010101010101010101", "balance": 42 },
+               { "address": "0101010101010101010101010101010101010101", "balance": 18446744073709551615 }"#,
+        );
+        assert!(ChainSpec::from_json(&overflowing).unwrap_err().contains("overflow"));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use log::warn;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const LOG_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "blocks.idx";
+// every index entry is a 32-byte block hash followed by a big endian u64 offset
+const INDEX_ENTRY_SIZE: usize = 40;
+
+/// Append-only block log with a hash -> offset index.
+///
+/// Each record in the log is a 4-byte big endian length followed by the bincode encoding of
+/// the block. Blocks are appended in insertion order, so replaying the log always visits a
+/// parent before its children.
+#[derive(Debug)]
+pub struct BlockStore {
+    log: File,
+    index_file: File,
+    index: HashMap<H256, u64>,
+    order: Vec<H256>,
+    log_len: u64,
+}
+
+impl BlockStore {
+    /// Open (or create) the block store under `dir`, recovering blocks that were written to the
+    /// log but not yet indexed and dropping a torn record at the end of the log.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOG_FILE))?;
+        let index_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(INDEX_FILE))?;
+        let log_len = log.metadata()?.len();
+        let mut store = Self {
+            log,
+            index_file,
+            index: HashMap::new(),
+            order: Vec::new(),
+            log_len,
+        };
+        store.load_index()?;
+        store.recover_log()?;
+        Ok(store)
+    }
+
+    /// Number of blocks in the store.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// Append a block to the log and the index. Appending a known block is a no-op.
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let hash = block.hash();
+        if self.index.contains_key(&hash) {
+            return Ok(());
+        }
+        let bytes = bincode::serialize(block)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let offset = self.log_len;
+        self.log.seek(SeekFrom::Start(offset))?;
+        self.log.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.log.write_all(&bytes)?;
+        self.log.sync_data()?;
+        self.log_len = offset + 4 + bytes.len() as u64;
+        self.write_index_entry(hash, offset)?;
+        self.index.insert(hash, offset);
+        self.order.push(hash);
+        Ok(())
+    }
+
+    /// Read a single block from the log.
+    pub fn get(&mut self, hash: &H256) -> io::Result<Option<Block>> {
+        match self.index.get(hash) {
+            Some(&offset) => self.read_record(offset).map(|(block, _)| Some(block)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read every block in the order it was appended.
+    pub fn blocks(&mut self) -> io::Result<Vec<Block>> {
+        let offsets: Vec<u64> = self.order.iter().map(|h| self.index[h]).collect();
+        let mut blocks = Vec::with_capacity(offsets.len());
+        for offset in offsets {
+            blocks.push(self.read_record(offset)?.0);
+        }
+        Ok(blocks)
+    }
+
+    fn load_index(&mut self) -> io::Result<()> {
+        let mut raw = Vec::new();
+        self.index_file.seek(SeekFrom::Start(0))?;
+        self.index_file.read_to_end(&mut raw)?;
+        let mut valid_len = 0;
+        for entry in raw.chunks_exact(INDEX_ENTRY_SIZE) {
+            let hash: [u8; 32] = entry[0..32].try_into().unwrap();
+            let offset = u64::from_be_bytes(entry[32..40].try_into().unwrap());
+            if offset >= self.log_len {
+                warn!("Block index points past the end of the log, truncating index");
+                break;
+            }
+            let hash = H256::from(hash);
+            self.index.insert(hash, offset);
+            self.order.push(hash);
+            valid_len += INDEX_ENTRY_SIZE;
+        }
+        if valid_len != raw.len() {
+            self.index_file.set_len(valid_len as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Index the records that follow the last indexed one, truncating the log at the first record
+    /// that cannot be read back completely.
+    fn recover_log(&mut self) -> io::Result<()> {
+        let mut offset = match self.order.last() {
+            Some(hash) => {
+                let last = self.index[hash];
+                last + self.read_record(last)?.1
+            }
+            None => 0,
+        };
+        while offset < self.log_len {
+            match self.read_record(offset) {
+                Ok((block, len)) => {
+                    let hash = block.hash();
+                    self.write_index_entry(hash, offset)?;
+                    self.index.insert(hash, offset);
+                    self.order.push(hash);
+                    offset += len;
+                }
+                Err(_) => {
+                    warn!("Dropping torn block record at offset {} of the log", offset);
+                    self.log.set_len(offset)?;
+                    self.log_len = offset;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the record at `offset`, returning the block and the total record length.
+    fn read_record(&mut self, offset: u64) -> io::Result<(Block, u64)> {
+        let mut size_buffer = [0u8; 4];
+        self.log.seek(SeekFrom::Start(offset))?;
+        self.log.read_exact(&mut size_buffer)?;
+        let size = u32::from_be_bytes(size_buffer) as u64;
+        if offset + 4 + size > self.log_len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block record"));
+        }
+        let mut buffer = vec![0u8; size as usize];
+        self.log.read_exact(&mut buffer)?;
+        let block = bincode::deserialize(&buffer)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((block, 4 + size))
+    }
+
+    fn write_index_entry(&mut self, hash: H256, offset: u64) -> io::Result<()> {
+        let mut entry = [0u8; INDEX_ENTRY_SIZE];
+        entry[0..32].copy_from_slice(hash.as_ref());
+        entry[32..40].copy_from_slice(&offset.to_be_bytes());
+        self.index_file.seek(SeekFrom::End(0))?;
+        self.index_file.write_all(&entry)?;
+        self.index_file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use std::path::PathBuf;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("bitcoin-store-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn reopen_and_recover() {
+        let dir = temp_dir();
+        let block_1 = generate_random_block(&generate_random_hash());
+        let block_2 = generate_random_block(&block_1.hash());
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&block_1).unwrap();
+            store.append(&block_2).unwrap();
+            store.append(&block_2).unwrap();
+            assert_eq!(store.len(), 2);
+        }
+        // lose the last index entry and tear a record at the end of the log
+        let index = OpenOptions::new().write(true).open(dir.join(INDEX_FILE)).unwrap();
+        index.set_len(INDEX_ENTRY_SIZE as u64).unwrap();
+        let mut log = OpenOptions::new().append(true).open(dir.join(LOG_FILE)).unwrap();
+        log.write_all(&[0, 0, 1, 0, 42]).unwrap();
+        drop(log);
+
+        let mut store = BlockStore::open(&dir).unwrap();
+        let hashes: Vec<H256> = store.blocks().unwrap().iter().map(|b| b.hash()).collect();
+        assert_eq!(hashes, vec![block_1.hash(), block_2.hash()]);
+        assert_eq!(store.get(&block_2.hash()).unwrap().unwrap().hash(), block_2.hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,7 +5,7 @@ use log::info;  // Keep this for logging
 use rand;
 
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::types::mempool::Mempool;
+use crate::types::mempool::{Mempool, MempoolError};
 use crate::blockchain::Blockchain;
 use crate::types::transaction::{Transaction, SignedTransaction};
 use crate::types::key_pair;
@@ -16,14 +16,14 @@ use crate::network::message::Message;
 use crate::error;
 use crate::types::hash::Hashable;
 use crate::network::server::Handle as ServerHandle;
-use crate::blockchain::retrieve_keypair;  // Import the standalone function
 
 
+#[derive(Clone)]
 pub struct TransactionGenerator {
     network: NetworkServerHandle,
     mempool: Arc<Mutex<Mempool>>,
     blockchain: Arc<Mutex<Blockchain>>,
-    keypair: ring::signature::Ed25519KeyPair,
+    keypair: Arc<Ed25519KeyPair>, // Sender of the generated transactions
 }
 
 impl TransactionGenerator {
@@ -31,20 +31,30 @@ impl TransactionGenerator {
         network: ServerHandle, 
         mempool: Arc<Mutex<Mempool>>, 
         blockchain: Arc<Mutex<Blockchain>>,
-        p2p_addr: std::net::SocketAddr,
+        keypair: Ed25519KeyPair,
     ) -> Self {
-        let keypair = retrieve_keypair(p2p_addr);
         let address = Address::from_public_key_bytes(keypair.public_key().as_ref());
         info!("Transaction generator initialized with address: {:?}", address);
         Self {
             network,
             mempool,
             blockchain,
-            keypair,
+            keypair: Arc::new(keypair),
         }
     }
 
-    pub fn start(self, theta: u64) {
+    /// Start generating transactions, unless our account has nothing to send at the tip
+    pub fn start(self, theta: u64) -> Result<(), String> {
+        let address = Address::from_public_key_bytes(self.keypair.public_key().as_ref());
+        let balance = {
+            let blockchain = self.blockchain.lock().unwrap();
+            blockchain.states[&blockchain.tip()]
+                .get_account_state(&address)
+                .map_or(0, |account| account.balance)
+        };
+        if balance == 0 {
+            return Err(format!("account {} of the transaction generator has no funds", address));
+        }
         info!("Transaction generator starting with theta {}", theta);
         thread::Builder::new()
             .name("transaction-generator".to_string())
@@ -52,6 +62,7 @@ impl TransactionGenerator {
                 self.generate_transactions(theta);
             })
             .unwrap();
+        Ok(())
     }
 
 
@@ -68,10 +79,11 @@ impl TransactionGenerator {
                     receiver,
                     value: rand::random::<u64>() % 100,  // Random value between 0-99
                     nonce,
+                    fee: rand::random::<u64>() % 10,
                 }
             };
-            info!("Created transaction: sender={:?}, receiver={:?}, value={}, nonce={}", 
-                  sender_address, transaction.receiver, transaction.value, transaction.nonce);
+            info!("Created transaction: sender={:?}, receiver={:?}, value={}, nonce={}, fee={}", 
+                  sender_address, transaction.receiver, transaction.value, transaction.nonce, transaction.fee);
             let signed_tx = SignedTransaction::new(transaction, &self.keypair);
             info!("Created transaction with hash: {:?}", signed_tx.hash());
             
@@ -80,20 +92,23 @@ impl TransactionGenerator {
                         // Insert into mempool before broadcasting
             {
                 let mut mempool = self.mempool.lock().unwrap();
-                if mempool.insert(signed_tx.clone()) {
-                    info!("Transaction added to mempool, broadcasting to network");
-                    // Only broadcast if successfully added to mempool
-                    self.network.broadcast(Message::NewTransactionHashes(vec![signed_tx.hash()]));
-                } else {
-                    info!("Transaction already in mempool, skipping broadcast");
+                match mempool.insert(signed_tx.clone()) {
+                    Ok(()) => {
+                        info!("Transaction added to mempool, broadcasting to network");
+                        // Only broadcast if successfully added to mempool
+                        self.network.broadcast(Message::NewTransactionHashes(vec![signed_tx.hash()]));
+                        // Increment nonce for next transaction
+                        nonce += 1;
+                    }
+                    // e.g. after a restart, when the chain already has our earlier transactions
+                    Err(MempoolError::StaleNonce { expected, .. }) => nonce = expected,
+                    // retry the same nonce later, e.g. once blocks confirm the pending ones
+                    Err(e) => info!("Transaction refused by mempool, skipping broadcast: {}", e),
                 }
                 info!("GENERATORMempool contains {} transactions", mempool.transactions.len());
                 
             }
 
-            // Increment nonce for next transaction
-            nonce += 1;
-
             if theta != 0 {
                 let interval = Duration::from_millis(1 * theta);
                 thread::sleep(interval);
@@ -101,17 +116,3 @@ impl TransactionGenerator {
         }
     }
 }
-
-impl Clone for TransactionGenerator {
-    fn clone(&self) -> Self {
-        // Use same keypair derivation as original
-        let keypair = retrieve_keypair(self.network.p2p_addr);
-        
-        Self {
-            network: self.network.clone(),
-            mempool: Arc::clone(&self.mempool),
-            blockchain: Arc::clone(&self.blockchain),
-            keypair,
-        }
-    }
-}
@@ -2,7 +2,10 @@
 #[macro_use]
 extern crate hex_literal;
 
-use crate::types::mempool::Mempool;
+use crate::types::mempool::{Mempool, MempoolConfig};
+use crate::types::address::Address;
+use crate::types::key_pair;
+use ring::signature::KeyPair;
 use log::LevelFilter;
 
 pub mod api;
@@ -13,6 +16,10 @@ pub mod network;
 pub mod generator;
 
 use blockchain::Blockchain;
+use blockchain::spec::ChainSpec;
+use network::address_book::AddressBook;
+use network::peer::NodeId;
+use network::transport::Encryption;
 use clap::clap_app;
 use smol::channel;
 use log::{error, info};
@@ -20,8 +27,6 @@ use api::Server as ApiServer;
 use std::net;
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time;
 
 fn main() {
 
@@ -34,7 +39,22 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets the number of outgoing connections kept using gossiped peer addresses")
+     (@arg encryption: --encryption [MODE] default_value("off") "Sets whether P2P connections are encrypted: off, on (when the peer supports it) or strict (refuse plaintext peers)")
+     (@arg ban_time: --("ban-time") [SECS] "Sets how long misbehaving peers stay banned, in seconds")
+     (@arg rbf_bump: --("rbf-bump") [PERCENT] "Sets the fee increase, in percent, for a transaction to replace a pending one with the same nonce")
+     (@arg mempool_max_txs: --("mempool-max-txs") [INT] "Sets the number of pending transactions kept in the mempool")
+     (@arg mempool_max_bytes: --("mempool-max-bytes") [BYTES] "Sets the total size of the pending transactions kept in the mempool")
+     (@arg mempool_max_per_sender: --("mempool-max-per-sender") [INT] "Sets the number of pending transactions kept for each sender")
+     (@arg mempool_ttl: --("mempool-ttl") [SECS] "Sets how long a transaction may stay pending before it is dropped, in seconds")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where the blockchain is persisted")
+     (@arg chain_spec: --("chain-spec") [FILE] "Sets the JSON file defining the genesis block and the consensus parameters")
+     (@arg retarget_interval: --("retarget-interval") [INT] "Sets the number of blocks between difficulty adjustments")
+     (@arg block_time: --("block-time") [MS] "Sets the target time between blocks in milliseconds")
+     (@arg miner_address: --("miner-address") [ADDR] conflicts_with("miner_key") "Sets the hex address credited with block rewards")
+     (@arg miner_key: --("miner-key") [FILE] "Sets a file holding the hex seed of the key credited with block rewards")
+     (@arg generator_key: --("generator-key") [FILE] "Sets a file holding the hex seed of the key the transaction generator sends from")
     )
     .get_matches();
 
@@ -61,17 +81,139 @@ fn main() {
             process::exit(1);
         });
 
+    // load the chain spec, then parse consensus parameters overriding it
+    let spec = match matches.value_of("chain_spec") {
+        Some(path) => ChainSpec::load(std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading chain spec {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => ChainSpec::default(),
+    };
+    let mut params = spec.params;
+    if let Some(interval) = matches.value_of("retarget_interval") {
+        params.retarget_interval = interval.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing retarget interval: {}", e);
+            process::exit(1);
+        });
+        if params.retarget_interval < 2 {
+            error!("Retarget interval must be at least 2 blocks");
+            process::exit(1);
+        }
+    }
+    if let Some(block_time) = matches.value_of("block_time") {
+        params.target_block_time = block_time.parse::<u128>().unwrap_or_else(|e| {
+            error!("Error parsing block time: {}", e);
+            process::exit(1);
+        });
+    }
+
+    let blockchain = match matches.value_of("data_dir") {
+        Some(dir) => Blockchain::open(std::path::Path::new(dir), params).unwrap_or_else(|e| {
+            error!("Error opening data directory {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => Blockchain::with_params(params),
+    };
+    info!("Running chain {} with genesis block {} and consensus parameters {}",
+          spec.name, blockchain.genesis_hash(), blockchain.params().digest());
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mut mempool_config = MempoolConfig::default();
+    if let Some(bump) = matches.value_of("rbf_bump") {
+        mempool_config.replace_bump = bump.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing replace-by-fee bump: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(max) = matches.value_of("mempool_max_txs") {
+        mempool_config.max_transactions = max.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing mempool transaction limit: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(max) = matches.value_of("mempool_max_bytes") {
+        mempool_config.max_bytes = max.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing mempool size limit: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(max) = matches.value_of("mempool_max_per_sender") {
+        mempool_config.max_per_sender = max.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing mempool limit per sender: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(ttl) = matches.value_of("mempool_ttl") {
+        let secs = ttl.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing mempool time-to-live: {}", e);
+            process::exit(1);
+        });
+        mempool_config.ttl = std::time::Duration::from_secs(secs);
+    }
+    let mempool = Arc::new(Mutex::new(Mempool::with_config(Arc::clone(&blockchain), mempool_config)));
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
+    // load the peer addresses learnt in previous runs, and the ones given on the command line
+    let mut address_book = match matches.value_of("data_dir") {
+        Some(dir) => AddressBook::open(&std::path::Path::new(dir).join("peers.json")).unwrap_or_else(|e| {
+            error!("Error opening address book in {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => AddressBook::new(),
+    };
+    if let Some(known_peers) = matches.values_of("known_peer") {
+        for addr in known_peers.filter_map(|peer| peer.parse::<net::SocketAddr>().ok()) {
+            address_book.add(addr);
+        }
+    }
+    let target_outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing number of outgoing connections: {}", e);
+            process::exit(1);
+        });
+
+    let ban_time = match matches.value_of("ban_time") {
+        Some(secs) => secs.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing ban time: {}", e);
+            process::exit(1);
+        }),
+        None => network::misbehavior::DEFAULT_BAN_TIME,
+    };
+
+    // the node key identifies us to peers, and is kept across runs when there is a data directory
+    let node_key = match matches.value_of("data_dir") {
+        Some(dir) => key_pair::load_or_generate(&std::path::Path::new(dir).join("node_key")).unwrap_or_else(|e| {
+            error!("Error loading node key in {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => key_pair::random(),
+    };
+    let node_id = NodeId::from_public_key_bytes(node_key.public_key().as_ref());
+    info!("Node ID is {}", node_id);
+    let encryption = matches
+        .value_of("encryption")
+        .unwrap()
+        .parse::<Encryption>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing encryption mode: {}", e);
+            process::exit(1);
+        });
+
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(
+        p2p_addr,
+        msg_tx,
+        Arc::clone(&blockchain),
+        address_book,
+        node_key,
+        network::server::Config { target_outbound, ban_time, encryption },
+    ).unwrap();
     server_ctx.start().unwrap();
 
-    let blockchain = Blockchain::new(); 
-    let blockchain = Arc::new(Mutex::new(blockchain));
-    let mempool = Arc::new(Mutex::new(Mempool::new(Arc::clone(&blockchain))));
-
     // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
@@ -88,52 +230,72 @@ fn main() {
         msg_rx,
         &server,
     );
+    let sync = worker_ctx.sync_state();
+    let orphan_pool = worker_ctx.orphan_pool();
     worker_ctx.start();
 
+    // without a key file, the miner and the generator use the built-in key of our port, as long
+    // as the genesis block funds it
+    let builtin_key = || blockchain::allocated_keypair(blockchain.lock().unwrap().params(), p2p_addr);
+
     // start the miner
+    let reward_address = if let Some(addr) = matches.value_of("miner_address") {
+        addr.parse::<Address>().unwrap_or_else(|e| {
+            error!("Error parsing miner address: {}", e);
+            process::exit(1);
+        })
+    } else {
+        let keypair = match matches.value_of("miner_key") {
+            Some(path) => key_pair::from_seed_file(std::path::Path::new(path)).unwrap_or_else(|e| {
+                error!("Error loading miner key from {}: {}", path, e);
+                process::exit(1);
+            }),
+            None => builtin_key().unwrap_or_else(|| {
+                error!("The genesis block does not fund the built-in key of {}, give --miner-key or --miner-address", p2p_addr);
+                process::exit(1);
+            }),
+        };
+        Address::from_public_key_bytes(keypair.public_key().as_ref())
+    };
+    info!("Block rewards are paid to {}", reward_address);
 
     let (miner_ctx, miner_handle, finished_block_chan) = miner::new(
         Arc::clone(&blockchain),
-        Arc::clone(&mempool)
+        Arc::clone(&mempool),
+        reward_address,
     );
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, Arc::clone(&blockchain));  // Pass blockchain to Worker
+    let miner_worker_ctx = miner::worker::Worker::new(
+        &server,
+        finished_block_chan,
+        Arc::clone(&blockchain),  // Pass blockchain to Worker
+        Arc::clone(&mempool),
+        &miner_handle,
+    );
+    mempool.lock().unwrap().set_miner(miner_handle.clone());
 
     miner_ctx.start();
     miner_worker_ctx.start();
 
-    // connect to known peers
+    // connect to known peers, and reconnect whenever they drop
     if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
+        for peer in known_peers {
+            match peer.parse::<net::SocketAddr>() {
+                Ok(addr) => server.add_configured_peer(addr),
+                Err(e) => error!("Error parsing peer address {}: {}", peer, e),
             }
-        });
+        }
     }
 
+    let generator_key = match matches.value_of("generator_key") {
+        Some(path) => Some(key_pair::from_seed_file(std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading generator key from {}: {}", path, e);
+            process::exit(1);
+        })),
+        None => builtin_key(),
+    };
+    if generator_key.is_none() {
+        info!("The genesis block does not fund the built-in key of {}, the transaction generator needs --generator-key", p2p_addr);
+    }
 
     // start the API server
     ApiServer::start(
@@ -142,6 +304,9 @@ fn main() {
         &server,
         &blockchain,
         &mempool,
+        &sync,
+        &orphan_pool,
+        generator_key,
     );
 
     loop {
@@ -1,15 +1,18 @@
 use std::sync::{Arc, Mutex};
 use crate::blockchain::Blockchain;
-use crate::types::hash::Hashable;
+use crate::types::hash::{H256, Hashable};
 use rand::Rng;
 pub mod worker;
-use log::info;
+use log::{debug, info};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time::{SystemTime, UNIX_EPOCH, Duration};
 use std::thread;
-use crate::types::block::Block;
+use crate::types::block::{Block, Content, Header};
 use crate::types::mempool::Mempool;
 use crate::types::block::compute_merkle_root;
+use crate::types::address::Address;
+use crate::types::transaction::SignedTransaction;
+use crate::types::state::State;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
@@ -17,6 +20,17 @@ enum ControlSignal {
     Exit,
 }
 
+/// Nonces tried between two looks at the control signals
+const UPDATE_CHECK_ATTEMPTS: u32 = 1000;
+
+/// The block being mined, all but the nonce
+struct Template {
+    header: Header,
+    data: Vec<SignedTransaction>, // The coinbase, then the transactions from the mempool
+    height: u64,
+    state: State, // After the parent, which the blockchain may not have
+}
+
 enum OperatingState {
     Paused,
     Run(u64),
@@ -30,15 +44,21 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     pub blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,  // Add this line
+    reward_address: Address, // Receiver of the coinbase of mined blocks
+    template: Option<Template>, // Rebuilt when the tip or the pending transactions change
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: Arc<Mutex<Blockchain>>, mempool: Arc<Mutex<Mempool>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    reward_address: Address,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
 
@@ -48,6 +68,8 @@ pub fn new(blockchain: Arc<Mutex<Blockchain>>, mempool: Arc<Mutex<Mempool>>) ->
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(&blockchain),
         mempool: Arc::clone(&mempool),  // Add this line
+        reward_address,
+        template: None,
     };
 
     let handle = Handle {
@@ -62,9 +84,10 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
     // Create a new, empty blockchain for testing purposes
     let blockchain = Blockchain::new();
     let blockchain = Arc::new(Mutex::new(blockchain));  // Wrap it in Arc<Mutex<>> for thread-safe access
+    let mempool = Arc::new(Mutex::new(Mempool::new(Arc::clone(&blockchain))));
 
     // Call the modified new() function, passing the new blockchain
-    new(Arc::clone(&blockchain))
+    new(Arc::clone(&blockchain), mempool, Address::default())
 }
 
 impl Handle {
@@ -79,7 +102,8 @@ impl Handle {
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        // nothing to update once the miner exited
+        let _ = self.control_chan.send(ControlSignal::Update);
     }
 }
 
@@ -111,7 +135,8 @@ impl Context {
                             self.operating_state = OperatingState::Run(i);
                         }
                         ControlSignal::Update => {
-                            // in paused state, don't need to update
+                            // in paused state, only forget the template, it is rebuilt on start
+                            self.template = None;
                         }
                     };
                     continue;
@@ -119,108 +144,54 @@ impl Context {
                 OperatingState::ShutDown => {
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
+                // take every pending signal, so that a burst of updates rebuilds the template once
+                _ => loop {
+                    match self.control_chan.try_recv() {
+                        Ok(signal) => {
+                            match signal {
+                                ControlSignal::Exit => {
+                                    info!("Miner shutting down");
+                                    self.operating_state = OperatingState::ShutDown;
+                                }
+                                ControlSignal::Start(i) => {
+                                    info!("Miner starting in continuous mode with lambda {}", i);
+                                    self.operating_state = OperatingState::Run(i);
+                                }
+                                ControlSignal::Update => {
+                                    self.template = None;
+                                }
+                            };
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                     }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
 
-            // Get transactions from mempool
-            //info!("Attempting to get transactions from mempool");
-            let transactions = {
-                let mut mempool = self.mempool.lock().expect("Failed to lock mempool");
-                let txs = mempool.validate_transactions();
-                //info!("Retrieved {} valid transactions from mempool", txs.len());
-                drop(mempool);
-                txs
+            let template = match self.template.take() {
+                Some(template) => template,
+                None => self.new_template(),
+            };
+            let (block, template) = self.mine(template);
+            let block = match block {
+                Some(block) => block,
+                None => {
+                    // look at the control signals again
+                    self.template = Some(template);
+                    continue;
+                }
             };
 
+            info!("Found valid block! Hash: {:?}, Nonce: {}", block.hash(), block.header.nonce);
 
-            // Only proceed with mining if there are transactions
-            if !transactions.is_empty() {
-                // 1. Get the parent block hash from the blockchain tip
-                let blockchain = self.blockchain.lock().expect("Failed to lock blockchain");
-                let mut parent_hash = blockchain.tip();
-                let parent_block = blockchain.blocks.get(&parent_hash).expect("Parent block not found");
-
-                // 2. Generate the current timestamp in milliseconds
-                let timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_millis();
-
-                // 3. Set difficulty as the same as the parent block
-                let difficulty = parent_block.get_difficulty();
-                drop(blockchain);
-
-                // 4. Compute the Merkle root with actual transactions
-                let merkle_root = compute_merkle_root(&transactions);
-
-                // 5. Mining loop: Generate a random nonce and create the block
-                info!("Starting proof-of-work loop");
-                let mut attempts = 0;
-                let mut rng = rand::thread_rng();
-                loop {
-                    let nonce: u32 = rng.gen();  // Generate a random nonce
-                    if attempts % 1000 == 0 {
-                        info!("Mining attempt {}", attempts);
-                    }
-                    let header = crate::types::block::Header {
-                        parent: parent_hash,
-                        nonce,
-                        difficulty,
-                        timestamp: timestamp as u128,
-                        merkle_root,
-                    };
-
-                    let block = crate::types::block::Block {
-                        header,
-                        content: crate::types::block::Content {
-                            data: transactions.clone(),
-                        },
-                    };
-
-
-                    
-                    // 6. Check if the block hash satisfies the difficulty
-                    if block.hash() <= difficulty {
-
-                     info!("Found valid block! Hash: {:?}, Nonce: {}, Attempts: {}", 
-                          block.hash(), nonce, attempts);
-
-                    {
-                        let mut mempool = self.mempool.lock().unwrap();
-                        mempool.remove_transactions(&transactions);
-                        // The lock will automatically be dropped at the end of this scope
-                    }
-
-                    // Send the block through the finished_block_chan
-                    info!("Sending mined block to worker for processing");
-                    self.finished_block_chan.send(block.clone()).expect("Failed to send finished block");
-                    parent_hash = block.hash();
-                    break;
-                    }
-                    attempts += 1;
-                }
-            }
+            // Send the block through the finished_block_chan
+            info!("Sending mined block to worker for processing");
+            self.finished_block_chan.send(block.clone()).expect("Failed to send finished block");
+            // keep going on top of our block, even before the worker inserts it
+            self.template = self.child_template(&block, &template);
 
             // Sleep if needed
             if let OperatingState::Run(i) = self.operating_state {
@@ -231,6 +202,107 @@ impl Context {
             }
         }
     }
+
+    /// Try up to UPDATE_CHECK_ATTEMPTS nonces on the template, returning the block if one works
+    fn mine(&self, mut template: Template) -> (Option<Block>, Template) {
+        let mut rng = rand::thread_rng();
+        for _ in 0..UPDATE_CHECK_ATTEMPTS {
+            template.header.nonce = rng.gen();
+            if template.header.hash() <= template.header.difficulty {
+                let block = Block {
+                    header: template.header.clone(),
+                    content: Content {
+                        data: template.data.clone(),
+                    },
+                };
+                return (Some(block), template);
+            }
+        }
+        (None, template)
+    }
+
+    /// Build a template on top of the blockchain tip
+    fn new_template(&self) -> Template {
+        // Get transactions from mempool
+        let transactions = {
+            let mempool = self.mempool.lock().expect("Failed to lock mempool");
+            mempool.validate_transactions(&self.reward_address)
+        };
+
+        // 1. Get the parent block hash from the blockchain tip
+        let blockchain = self.blockchain.lock().expect("Failed to lock blockchain");
+        let parent = blockchain.tip();
+        let height = blockchain.height(&parent).expect("Parent block not found") as u64 + 1;
+
+        // 2. Generate the current timestamp in milliseconds, past the parent's median time
+        let timestamp = now().max(blockchain.min_timestamp(&parent));
+
+        // 3. Set difficulty following the retarget rule
+        let difficulty = blockchain.next_difficulty(&parent).expect("Parent block not found");
+        let state = blockchain.states[&parent].clone();
+        drop(blockchain);
+        debug!("New block template at height {} with {} transactions", height, transactions.len());
+        self.template(parent, height, difficulty, timestamp, transactions, state)
+    }
+
+    /// Build a template on top of a block we just mined from `template`, which the blockchain may
+    /// not have yet. Returns None, so that the template is built on the tip instead, when the
+    /// blockchain has it already or when the difficulty may change at the next height.
+    fn child_template(&self, block: &Block, template: &Template) -> Option<Template> {
+        let blockchain = self.blockchain.lock().expect("Failed to lock blockchain");
+        let height = template.height + 1;
+        if blockchain.has_header(&block.hash()) || height.is_multiple_of(blockchain.params().retarget_interval) {
+            return None;
+        }
+        let state = blockchain.state_after(block, template.height, template.state.clone()).ok()?;
+        drop(blockchain);
+        // the mempool still holds the transactions of `block` until the worker inserts it; they
+        // no longer apply on the state after it, unlike the ones that depend on them
+        let transactions = {
+            let mempool = self.mempool.lock().expect("Failed to lock mempool");
+            mempool.validate_transactions_on(state.clone(), &self.reward_address)
+        };
+        let timestamp = now().max(block.header.timestamp);
+        Some(self.template(block.hash(), height, block.header.difficulty, timestamp, transactions, state))
+    }
+
+    fn template(
+        &self,
+        parent: H256,
+        height: u64,
+        difficulty: H256,
+        timestamp: u128,
+        transactions: Vec<SignedTransaction>,
+        state: State,
+    ) -> Template {
+        // 4. Pay the block subsidy to our reward address in a coinbase ahead of the transactions
+        let subsidy = self.blockchain.lock().expect("Failed to lock blockchain").params().block_subsidy(height);
+        let coinbase = SignedTransaction::coinbase(self.reward_address, subsidy, height as u32);
+        let mut data = vec![coinbase];
+        data.extend(transactions);
+
+        // 5. Compute the Merkle root with actual transactions
+        let merkle_root = compute_merkle_root(&data);
+        Template {
+            header: Header {
+                parent,
+                nonce: 0,
+                difficulty,
+                timestamp,
+                merkle_root,
+            },
+            data,
+            height,
+            state,
+        }
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -239,6 +311,18 @@ impl Context {
 mod test {
     use ntest::timeout;
     use crate::types::hash::Hashable;
+    use std::sync::{Arc, Mutex};
+    use crate::blockchain::{retrieve_keypair, Blockchain, ConsensusParams};
+    use crate::types::address::Address;
+    use crate::types::block::{compute_merkle_root, generate_random_block_at, Block};
+    use crate::types::mempool::Mempool;
+    use crate::types::transaction::{SignedTransaction, Transaction};
+
+    fn test_miner() -> (super::Context, super::Handle, crossbeam::channel::Receiver<Block>) {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(ConsensusParams::for_test())));
+        let mempool = Arc::new(Mutex::new(Mempool::new(Arc::clone(&blockchain))));
+        super::new(blockchain, mempool, Address::default())
+    }
 
     #[test]
     #[timeout(60000)]
@@ -255,6 +339,57 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    fn child_template_follows_block() {
+        let (miner_ctx, _miner_handle, _finished_block_chan) = test_miner();
+        let key = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let transfer = |nonce| SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value: 10, nonce, fee: 0 },
+            &key,
+        );
+        let (first, second) = (transfer(1), transfer(2));
+        {
+            let mut mempool = miner_ctx.mempool.lock().unwrap();
+            mempool.insert(first.clone()).unwrap();
+            mempool.insert(second.clone()).unwrap();
+        }
+
+        // a block of ours confirms the first transaction, the blockchain does not have it yet
+        let genesis = miner_ctx.blockchain.lock().unwrap().tip();
+        let mut block = generate_random_block_at(&genesis, 1);
+        block.content.data.push(first);
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+
+        // the template on top of it leaves the first out, but takes the second that depends on it
+        let parent = miner_ctx.new_template();
+        let template = miner_ctx.child_template(&block, &parent).unwrap();
+        assert_eq!(template.header.parent, block.hash());
+        let hashes: Vec<_> = template.data[1..].iter().map(|tx| tx.hash()).collect();
+        assert_eq!(hashes, vec![second.hash()]);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn update_rebuilds_template() {
+        let (miner_ctx, miner_handle, finished_block_chan) = test_miner();
+        let blockchain = Arc::clone(&miner_ctx.blockchain);
+        miner_ctx.start();
+        // pause 100ms after each block, so that the update lands before the next one
+        miner_handle.start(100_000);
+        let first = finished_block_chan.recv().unwrap();
+        let second = finished_block_chan.recv().unwrap();
+        assert_eq!(second.get_parent(), first.hash());
+
+        // another block takes the tip, the miner only learns it through the update
+        let genesis = blockchain.lock().unwrap().tip();
+        let other = generate_random_block_at(&genesis, 1);
+        blockchain.lock().unwrap().insert(&other).unwrap();
+        miner_handle.update();
+        let next = finished_block_chan.recv().unwrap();
+        assert_eq!(next.get_parent(), other.hash());
+        miner_handle.exit();
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
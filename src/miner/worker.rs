@@ -1,5 +1,5 @@
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use log::{debug, error, info};
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
@@ -7,6 +7,8 @@ use std::sync::{Arc, Mutex};
 use crate::Blockchain;
 use crate::types::hash::Hashable;
 use crate::network::message::Message;
+use crate::types::mempool::Mempool;
+use super::Handle as MinerHandle;
 
 
 
@@ -15,6 +17,8 @@ pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
     pub blockchain: Arc<Mutex<Blockchain>>, // Add blockchain
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,
 }
 
 impl Worker {
@@ -22,11 +26,15 @@ impl Worker {
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
         blockchain: Arc<Mutex<Blockchain>>, // Add blockchain argument
+        mempool: Arc<Mutex<Mempool>>,
+        miner: &MinerHandle,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
             blockchain: Arc::clone(&blockchain), // Clone and store blockchain
+            mempool,
+            miner: miner.clone(),
         }
     }
 
@@ -45,13 +53,23 @@ impl Worker {
             let block = self.finished_block_chan.recv().expect("Receive finished block error");
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
              // Lock the blockchain and insert the block
-            {
-                //println!("Inserting block with hash: {:?}", block.hash());
+            let tip_change = {
                 let mut blockchain = self.blockchain.lock().expect("Failed to lock the blockchain");
-                blockchain.insert(&block).expect("Failed to insert block into blockchain");
+                let result = blockchain.insert(&block);
                 drop(blockchain);
-                //println!("Blockchain tip updated to block hash: {:?}", block.hash());
-            } // The lock is automatically released here
+                match result {
+                    Ok(tip_change) => tip_change,
+                    Err(e) => {
+                        error!("Mined block {:?} rejected by the blockchain: {}", block.hash(), e);
+                        // the miner may be building on it, start over from the tip
+                        self.miner.update();
+                        continue;
+                    }
+                }
+            }; // The lock is automatically released here
+            if let Some(change) = tip_change {
+                self.mempool.lock().unwrap().handle_tip_change(&change);
+            }
             
             // Logging the insertion of the block
             //info!("Block inserted into the blockchain: {:?}", block.hash());
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most addresses remembered, the ones seen least recently are forgotten first
+pub const MAX_ADDRESSES: usize = 1000;
+/// Most addresses sent in, or accepted from, one `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 100;
+/// An address we failed to connect to is not dialed again before this many seconds
+const RETRY_INTERVAL: u64 = 60;
+
+/// What we know about a peer address, times in seconds since the Unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AddressInfo {
+    pub last_seen: u64, // Last time we heard about it, from the peer itself or through gossip
+    pub last_success: Option<u64>, // Last time we completed a handshake with it
+    pub last_attempt: Option<u64>, // Last time we dialed it
+}
+
+/// Peer addresses learnt from connections and gossip, optionally persisted to a JSON file
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, AddressInfo>,
+    path: Option<PathBuf>,
+    dirty: bool, // Changed since it was last saved
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the book saved at `path`, or start an empty one there
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(AddressBook {
+            entries,
+            path: Some(path.to_path_buf()),
+            dirty: false,
+        })
+    }
+
+    /// Write the book to its file, if it has one and it changed
+    pub fn save(&mut self) -> io::Result<()> {
+        if let (Some(path), true) = (self.path.as_ref(), self.dirty) {
+            // write a temporary file first, so that a crash never leaves a truncated book
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, serde_json::to_string_pretty(&self.entries)?)?;
+            std::fs::rename(&tmp, path)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddressInfo> {
+        self.entries.get(addr)
+    }
+
+    /// Record that `addr` was heard of just now
+    pub fn add(&mut self, addr: SocketAddr) {
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return;
+        }
+        self.entries.entry(addr).or_default().last_seen = now();
+        self.dirty = true;
+        if self.entries.len() > MAX_ADDRESSES {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, info)| (info.last_success.is_some(), info.last_seen))
+                .map(|(addr, _)| *addr)
+                .unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        if self.entries.remove(addr).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    pub fn mark_attempt(&mut self, addr: SocketAddr) {
+        self.add(addr);
+        if let Some(info) = self.entries.get_mut(&addr) {
+            info.last_attempt = Some(now());
+        }
+    }
+
+    pub fn mark_success(&mut self, addr: SocketAddr) {
+        self.add(addr);
+        if let Some(info) = self.entries.get_mut(&addr) {
+            info.last_success = Some(now());
+        }
+    }
+
+    /// Addresses to gossip, the most recently seen first
+    pub fn sample(&self, max: usize, exclude: &SocketAddr) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &AddressInfo)> = self.entries.iter()
+            .filter(|(addr, _)| *addr != exclude)
+            .collect();
+        addrs.sort_by_key(|(_, info)| std::cmp::Reverse(info.last_seen));
+        addrs.into_iter().take(max).map(|(addr, _)| *addr).collect()
+    }
+
+    /// Addresses worth dialing, leaving out `exclude` and the ones we recently failed to reach.
+    /// Addresses that worked before come first, then the most recently seen.
+    pub fn candidates(&self, exclude: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let now = now();
+        let mut addrs: Vec<(&SocketAddr, &AddressInfo)> = self.entries.iter()
+            .filter(|(addr, _)| !exclude.contains(addr))
+            .filter(|(_, info)| {
+                let failed = match (info.last_attempt, info.last_success) {
+                    (Some(attempt), Some(success)) => attempt > success,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                };
+                !failed || now >= info.last_attempt.unwrap() + RETRY_INTERVAL
+            })
+            .collect();
+        addrs.sort_by_key(|(_, info)| std::cmp::Reverse((info.last_success, info.last_seen)));
+        addrs.into_iter().map(|(addr, _)| *addr).collect()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persist_and_pick() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-peers-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("peers.json");
+        let a: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6000".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:6000".parse().unwrap();
+
+        let mut book = AddressBook::open(&path).unwrap();
+        book.add(a);
+        book.mark_success(b);
+        book.mark_attempt(c); // never answered
+        book.add("0.0.0.0:6000".parse().unwrap());
+        book.save().unwrap();
+
+        let book = AddressBook::open(&path).unwrap();
+        assert_eq!(book.len(), 3);
+        assert!(book.get(&b).unwrap().last_success.is_some());
+        // the one that worked comes first, the one that failed is left out
+        assert_eq!(book.candidates(&HashSet::new()), vec![b, a]);
+        let connected: HashSet<SocketAddr> = vec![b].into_iter().collect();
+        assert_eq!(book.candidates(&connected), vec![a]);
+        assert!(!book.sample(10, &a).contains(&a));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use super::message::Message;
+use super::misbehavior::Misbehavior;
+use bincode::Options;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io;
+
+/// Largest frame we read from a peer, in bytes, not counting the 4-byte length header
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// Why a frame could not be turned into a message
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error), // The connection closed or broke
+    TooLarge { limit: usize, found: usize }, // The length header is over MAX_FRAME_SIZE
+    Malformed(String), // The payload does not decode to a message
+    LimitExceeded(String), // The message decodes, but a list in it is longer than allowed
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "connection error: {}", e),
+            FrameError::TooLarge { limit, found } => {
+                write!(f, "frame of {} bytes, limit {}", found, limit)
+            }
+            FrameError::Malformed(e) => write!(f, "malformed message: {}", e),
+            FrameError::LimitExceeded(e) => write!(f, "message over the limits: {}", e),
+        }
+    }
+}
+
+impl FrameError {
+    /// What the peer did wrong, if anything
+    pub fn misbehavior(&self) -> Option<Misbehavior> {
+        match self {
+            FrameError::Io(_) => None,
+            FrameError::Malformed(_) => Some(Misbehavior::MalformedMessage),
+            FrameError::TooLarge { .. } | FrameError::LimitExceeded(_) => Some(Misbehavior::OversizedMessage),
+        }
+    }
+
+    /// Whether we should stop talking to the peer. A frame that is too large leaves the stream
+    /// out of sync, and a peer ignoring the limits is not worth our memory.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, FrameError::Malformed(_))
+    }
+}
+
+/// Read one frame: a big-endian u32 length, then that many bytes. The length is checked before
+/// anything is allocated for the payload.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, FrameError> {
+    let mut size_buffer: [u8; 4] = [0; 4];
+    reader.read_exact(&mut size_buffer).await.map_err(FrameError::Io)?;
+    let size = u32::from_be_bytes(size_buffer) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge { limit: MAX_FRAME_SIZE, found: size });
+    }
+    let mut payload = vec![0; size];
+    reader.read_exact(&mut payload).await.map_err(FrameError::Io)?;
+    Ok(payload)
+}
+
+/// Write one frame and flush it
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+/// Decode the payload of a frame and check the message against the per-message limits
+pub fn decode(payload: &[u8]) -> Result<Message, FrameError> {
+    // the encoding of bincode::serialize, without tolerating bytes after the message
+    let msg: Message = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_FRAME_SIZE as u64)
+        .deserialize(payload)
+        .map_err(|e| FrameError::Malformed(e.to_string()))?;
+    msg.check_limits().map_err(FrameError::LimitExceeded)?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::message::MAX_BLOCKS;
+    use crate::types::hash::H256;
+    use futures::io::Cursor;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn read(bytes: Vec<u8>) -> Result<Message, FrameError> {
+        let payload = smol::block_on(read_frame(&mut Cursor::new(bytes)))?;
+        decode(&payload)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = Cursor::new(Vec::new());
+        let payload = bincode::serialize(&Message::GetBlocks(vec![H256::default()])).unwrap();
+        smol::block_on(write_frame(&mut bytes, &payload)).unwrap();
+        assert!(matches!(read(bytes.into_inner()), Ok(Message::GetBlocks(hashes)) if hashes.len() == 1));
+    }
+
+    #[test]
+    fn reject_hostile_frames() {
+        // a length header asking for 4 GiB is refused before allocating anything
+        let huge = u32::MAX.to_be_bytes().to_vec();
+        assert!(matches!(read(huge), Err(FrameError::TooLarge { found, .. }) if found == u32::MAX as usize));
+
+        // a frame shorter than its header says is a closed connection
+        let mut truncated = frame(&[1, 2, 3, 4]);
+        truncated.truncate(6);
+        assert!(matches!(read(truncated), Err(FrameError::Io(_))));
+
+        // garbage, and a valid message followed by garbage
+        assert!(matches!(read(frame(&[0xff; 16])), Err(FrameError::Malformed(_))));
+        let mut trailing = bincode::serialize(&Message::GetAddr).unwrap();
+        trailing.push(0);
+        assert!(matches!(read(frame(&trailing)), Err(FrameError::Malformed(_))));
+
+        // a list whose length prefix promises more elements than the frame holds
+        let mut lying = bincode::serialize(&Message::GetBlocks(vec![])).unwrap();
+        let len = lying.len();
+        lying[len - 8..].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(read(frame(&lying)), Err(FrameError::Malformed(_))));
+
+        // a well-formed message over the per-message limits
+        let hashes = vec![H256::default(); MAX_BLOCKS + 1];
+        let too_many = bincode::serialize(&Message::GetBlocks(hashes)).unwrap();
+        let error = read(frame(&too_many)).unwrap_err();
+        assert!(matches!(error, FrameError::LimitExceeded(_)));
+        assert!(error.is_fatal());
+        assert_eq!(error.misbehavior(), Some(Misbehavior::OversizedMessage));
+    }
+}
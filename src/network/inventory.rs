@@ -0,0 +1,154 @@
+use crate::types::hash::H256;
+use std::collections::{HashMap, VecDeque};
+
+/// Most hashes remembered per peer, the least recently seen are forgotten first
+pub const MAX_KNOWN_INVENTORY: usize = 10000;
+
+/// A set of hashes bounded to the most recently used ones
+#[derive(Debug)]
+pub struct KnownSet {
+    capacity: usize,
+    entries: HashMap<H256, u64>, // Hash -> when it was last used
+    order: VecDeque<(H256, u64)>, // Uses, oldest first; stale ones are skipped on eviction
+    clock: u64,
+}
+
+impl KnownSet {
+    pub fn new(capacity: usize) -> Self {
+        KnownSet {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Remember `hash` as just used, returning whether it was new
+    pub fn insert(&mut self, hash: H256) -> bool {
+        self.clock += 1;
+        let new = self.entries.insert(hash, self.clock).is_none();
+        self.order.push_back((hash, self.clock));
+        while self.entries.len() > self.capacity {
+            let (oldest, used) = self.order.pop_front().unwrap();
+            if self.entries.get(&oldest) == Some(&used) {
+                self.entries.remove(&oldest);
+            }
+        }
+        // drop the stale uses once they pile up
+        if self.order.len() > 2 * self.capacity.max(1) {
+            let entries = &self.entries;
+            self.order.retain(|(hash, used)| entries.get(hash) == Some(used));
+        }
+        new
+    }
+
+    /// Forget `hash`, returning whether it was in the set
+    pub fn remove(&mut self, hash: &H256) -> bool {
+        self.entries.remove(hash).is_some()
+    }
+}
+
+/// What a peer is known to have, either because it sent it to us or because we sent it, the
+/// announcements waiting for the next trickle, and the blocks we asked it for
+#[derive(Debug)]
+pub struct PeerInventory {
+    known: KnownSet,
+    blocks: Vec<H256>,
+    transactions: Vec<H256>,
+    requested: KnownSet, // Blocks asked for and not received yet
+}
+
+impl Default for PeerInventory {
+    fn default() -> Self {
+        PeerInventory {
+            known: KnownSet::new(MAX_KNOWN_INVENTORY),
+            blocks: Vec::new(),
+            transactions: Vec::new(),
+            requested: KnownSet::new(MAX_KNOWN_INVENTORY),
+        }
+    }
+}
+
+impl PeerInventory {
+    pub fn mark_known(&mut self, hash: H256) {
+        self.known.insert(hash);
+    }
+
+    pub fn is_known(&self, hash: &H256) -> bool {
+        self.known.contains(hash)
+    }
+
+    /// Queue a block announcement, unless the peer already has the block
+    pub fn queue_block(&mut self, hash: H256) {
+        if self.known.insert(hash) {
+            self.blocks.push(hash);
+        }
+    }
+
+    /// Queue a transaction announcement, unless the peer already has the transaction
+    pub fn queue_transaction(&mut self, hash: H256) {
+        if self.known.insert(hash) {
+            self.transactions.push(hash);
+        }
+    }
+
+    pub fn mark_requested(&mut self, hash: H256) {
+        self.requested.insert(hash);
+    }
+
+    /// Forget the request for a block, returning whether there was one
+    pub fn take_requested(&mut self, hash: &H256) -> bool {
+        self.requested.remove(hash)
+    }
+
+    /// The block and transaction hashes to announce now
+    pub fn take(&mut self) -> (Vec<H256>, Vec<H256>) {
+        (std::mem::take(&mut self.blocks), std::mem::take(&mut self.transactions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_forgotten() {
+        let mut known = KnownSet::new(2);
+        let (a, b, c) = (H256::from([1; 32]), H256::from([2; 32]), H256::from([3; 32]));
+        assert!(known.insert(a));
+        assert!(known.insert(b));
+        assert!(!known.insert(a)); // a is now the most recent
+        known.insert(c);
+        assert_eq!(known.len(), 2);
+        assert!(known.contains(&a) && known.contains(&c) && !known.contains(&b));
+        assert!(known.remove(&a));
+        assert!(!known.remove(&a));
+        assert!(known.insert(b));
+        assert_eq!(known.len(), 2);
+    }
+
+    #[test]
+    fn announce_once() {
+        let mut inventory = PeerInventory::default();
+        let (a, b) = (H256::from([1; 32]), H256::from([2; 32]));
+        inventory.mark_known(a); // the peer sent it to us
+        inventory.queue_block(a);
+        inventory.queue_block(b);
+        inventory.queue_block(b);
+        inventory.queue_transaction(b);
+        assert_eq!(inventory.take(), (vec![b], vec![]));
+        assert_eq!(inventory.take(), (vec![], vec![]));
+    }
+}
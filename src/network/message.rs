@@ -1,6 +1,24 @@
 use serde::{Serialize, Deserialize};
+use ring::signature::{self, Ed25519KeyPair};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::{hash::H256, block::{Block, Header}, transaction::SignedTransaction};
+use super::address_book::MAX_ADDR_PER_MESSAGE;
+use super::peer::NodeId;
+use super::sync::MAX_HEADERS;
+
+/// Version of the wire protocol, peers speaking another version are dropped
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Most blocks asked for in one `GetBlocks`, or sent in one `Blocks`
+pub const MAX_BLOCKS: usize = 128;
+/// Most hashes announced in one `NewBlockHashes` or `NewTransactionHashes`, or asked for in one
+/// `GetTransactions`, and most transactions in one `Transactions`
+pub const MAX_INVENTORY: usize = 1000;
+/// Most hashes in the block locator of a `GetHeaders`
+pub const MAX_LOCATOR: usize = 64;
+/// Longest `Ping` or `Pong` payload, in bytes
+pub const MAX_PING_SIZE: usize = 64;
+/// Signed together with the challenge of a Version, so that the signature means nothing elsewhere
+const VERSION_CONTEXT: &[u8] = b"bitcoin p2p version";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -12,4 +30,66 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    Version(Version),
+    VerAck(Vec<u8>), // Signature by the node key over the challenge of the accepted Version
+    GetHeaders(Vec<H256>), // Block locator of the requesting node
+    Headers(Vec<Header>),
+    GetAddr,
+    Addr(Vec<std::net::SocketAddr>),
+}
+
+impl Message {
+    /// Check the lists in the message against the protocol limits, so that a peer cannot make us
+    /// work through arbitrarily long requests
+    pub fn check_limits(&self) -> Result<(), String> {
+        let (what, found, limit) = match self {
+            Message::Ping(s) | Message::Pong(s) => ("ping bytes", s.len(), MAX_PING_SIZE),
+            Message::NewBlockHashes(v) => ("block hashes", v.len(), MAX_INVENTORY),
+            Message::GetBlocks(v) => ("blocks", v.len(), MAX_BLOCKS),
+            Message::Blocks(v) => ("blocks", v.len(), MAX_BLOCKS),
+            Message::NewTransactionHashes(v) | Message::GetTransactions(v) => {
+                ("transaction hashes", v.len(), MAX_INVENTORY)
+            }
+            Message::Transactions(v) => ("transactions", v.len(), MAX_INVENTORY),
+            Message::GetHeaders(v) => ("locator hashes", v.len(), MAX_LOCATOR),
+            Message::Headers(v) => ("headers", v.len(), MAX_HEADERS),
+            Message::Addr(v) => ("addresses", v.len(), MAX_ADDR_PER_MESSAGE),
+            Message::Version(_) | Message::VerAck(_) | Message::GetAddr => return Ok(()),
+        };
+        if found > limit {
+            return Err(format!("{} {}, limit {}", found, what, limit));
+        }
+        Ok(())
+    }
+}
+
+/// First message on every connection, in both directions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub version: u32,
+    pub genesis: H256,
+    pub params: H256, // Digest of the consensus parameters
+    pub best_height: u64,
+    pub nonce: u64, // Random per node, so that a node notices when it connects to itself
+    pub listen_addr: std::net::SocketAddr,
+    pub node_id: NodeId, // Proven in the VerAck, as anyone can replay the Version of a node
+    pub challenge: H256, // Random per connection, for the peer to sign in its VerAck
+}
+
+/// The signature of a VerAck: `challenge`, from the Version of the peer, signed with our node key
+pub fn sign_challenge(node_key: &Ed25519KeyPair, challenge: &H256) -> Vec<u8> {
+    node_key.sign(&challenge_bytes(challenge)).as_ref().to_vec()
+}
+
+/// Whether `signature`, from a VerAck, is by the key of `node_id` over our `challenge`
+pub fn verify_challenge(node_id: &NodeId, challenge: &H256, signature: &[u8]) -> bool {
+    signature::UnparsedPublicKey::new(&signature::ED25519, node_id.as_bytes())
+        .verify(&challenge_bytes(challenge), signature)
+        .is_ok()
+}
+
+fn challenge_bytes(challenge: &H256) -> Vec<u8> {
+    let mut signed = VERSION_CONTEXT.to_vec();
+    signed.extend_from_slice(challenge.as_ref());
+    signed
 }
@@ -0,0 +1,125 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Score at which a peer is disconnected and its address banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// Seconds an address stays banned unless configured otherwise
+pub const DEFAULT_BAN_TIME: u64 = 24 * 60 * 60;
+
+/// Things a peer can do wrong, each adding to its score
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    MalformedMessage, // The frame does not decode to a message
+    OversizedMessage, // The frame, or a list in the message, is larger than allowed
+    InvalidBlock, // A block or header breaking a consensus rule
+    InvalidTransaction, // A transaction that could never be valid, e.g. a bad signature
+    Unsolicited, // Something we did not ask for, or not at this point of the connection
+}
+
+impl Misbehavior {
+    pub fn score(self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 50,
+            Misbehavior::OversizedMessage => 50,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::Unsolicited => 10,
+        }
+    }
+}
+
+impl std::fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Misbehavior::MalformedMessage => write!(f, "malformed message"),
+            Misbehavior::OversizedMessage => write!(f, "oversized message"),
+            Misbehavior::InvalidBlock => write!(f, "invalid block"),
+            Misbehavior::InvalidTransaction => write!(f, "invalid transaction"),
+            Misbehavior::Unsolicited => write!(f, "unsolicited message"),
+        }
+    }
+}
+
+/// A banned IP address, times in seconds since the Unix epoch
+#[derive(Serialize, Debug, Clone)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub until: u64,
+    pub reason: String,
+}
+
+/// IP addresses we refuse to talk to. A ban covers every port, since a node picks the port it
+/// connects from and may claim any port to listen on.
+#[derive(Debug, Default)]
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ban `ip` for `duration` seconds from now, replacing an earlier ban
+    pub fn ban(&mut self, ip: IpAddr, duration: u64, reason: &str) {
+        let ban = Ban {
+            ip,
+            until: now().saturating_add(duration),
+            reason: reason.to_string(),
+        };
+        self.bans.insert(ip, ban);
+    }
+
+    /// Lift the ban on `ip`, returning whether there was one
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.get(ip).is_some_and(|ban| ban.until > now())
+    }
+
+    /// The bans still in force, the ones ending first at the front
+    pub fn list(&mut self) -> Vec<Ban> {
+        let now = now();
+        self.bans.retain(|_, ban| ban.until > now);
+        let mut bans: Vec<Ban> = self.bans.values().cloned().collect();
+        bans.sort_by_key(|ban| (ban.until, ban.ip));
+        bans
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_and_expire() {
+        let mut bans = BanList::new();
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        bans.ban(a, 60, "invalid block");
+        bans.ban(b, 0, "malformed message"); // already over
+        assert!(bans.is_banned(&a));
+        assert!(!bans.is_banned(&b));
+        assert!(!bans.is_banned(&"10.0.0.3".parse().unwrap()));
+
+        let list = bans.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].ip, a);
+        assert_eq!(list[0].reason, "invalid block");
+
+        assert!(bans.unban(&a));
+        assert!(!bans.unban(&a));
+        assert!(!bans.is_banned(&a));
+    }
+}
@@ -1,4 +1,11 @@
+pub mod address_book;
+pub mod framing;
+pub mod inventory;
 pub mod message;
+pub mod misbehavior;
+pub mod orphan;
 pub mod peer;
 pub mod server;
+pub mod sync;
+pub mod transport;
 pub mod worker;
@@ -0,0 +1,241 @@
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use log::debug;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most blocks kept waiting for their parent, the oldest are dropped first
+pub const MAX_ORPHANS: usize = 750;
+/// Most orphans kept from a single peer, so that one peer cannot fill the pool
+pub const MAX_ORPHANS_PER_PEER: usize = 100;
+/// An orphan whose parent has not shown up after this long is dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan {
+    block: Block,
+    peer: SocketAddr, // Who sent it
+    received: Instant,
+}
+
+/// Blocks whose parent we do not have yet, shared by the network workers, since a block and its
+/// parent may be handled by different ones
+#[derive(Default)]
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>,
+    children: HashMap<H256, Vec<H256>>, // Parent hash -> orphans waiting for it
+}
+
+/// Why an orphan was not added to the pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanError {
+    Duplicate,
+    PeerQuota, // The peer already has MAX_ORPHANS_PER_PEER blocks in the pool
+}
+
+/// An orphan, as reported through the API
+#[derive(Serialize, Debug, Clone)]
+pub struct OrphanInfo {
+    pub hash: String,
+    pub parent: String,
+    pub peer: SocketAddr,
+    pub age_secs: u64,
+}
+
+/// The content of the pool, as reported through the API
+#[derive(Serialize, Debug, Clone)]
+pub struct OrphanPoolStatus {
+    pub count: usize,
+    pub limit: usize,
+    pub per_peer_limit: usize,
+    pub expiry_secs: u64,
+    pub per_peer: HashMap<SocketAddr, usize>,
+    pub orphans: Vec<OrphanInfo>, // The oldest first
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Add a block received from `peer` whose parent is missing. Expired orphans are dropped first,
+    /// then the oldest one if the pool is full.
+    pub fn insert(&mut self, block: Block, peer: SocketAddr) -> Result<(), OrphanError> {
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return Err(OrphanError::Duplicate);
+        }
+        self.expire();
+        if self.orphans.values().filter(|orphan| orphan.peer == peer).count() >= MAX_ORPHANS_PER_PEER {
+            return Err(OrphanError::PeerQuota);
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            let oldest = self.orphans.iter()
+                .min_by_key(|(_, orphan)| orphan.received)
+                .map(|(hash, _)| *hash)
+                .unwrap();
+            debug!("Orphan pool full, dropping {:?}", oldest);
+            self.remove(&oldest);
+        }
+        self.children.entry(block.get_parent()).or_default().push(hash);
+        self.orphans.insert(hash, Orphan { block, peer, received: Instant::now() });
+        Ok(())
+    }
+
+    /// Drop the orphans older than ORPHAN_EXPIRY, returning how many there were
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<H256> = self.orphans.iter()
+            .filter(|(_, orphan)| now.duration_since(orphan.received) > ORPHAN_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            debug!("Orphan {:?} expired", hash);
+            self.remove(hash);
+        }
+        expired.len()
+    }
+
+    /// Take the orphans waiting for `parent`, now that it is connected
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        hashes.iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .map(|orphan| orphan.block)
+            .collect()
+    }
+
+    /// Drop every orphan descending from `hash`, e.g. once the block turned out invalid,
+    /// returning how many there were
+    pub fn remove_descendants(&mut self, hash: &H256) -> usize {
+        let mut removed = 0;
+        let mut parents = vec![*hash];
+        while let Some(parent) = parents.pop() {
+            for child in self.children.remove(&parent).unwrap_or_default() {
+                if self.orphans.remove(&child).is_some() {
+                    removed += 1;
+                    parents.push(child);
+                }
+            }
+        }
+        removed
+    }
+
+    /// The block to ask for so that `parent` can be connected: `parent` itself, or, if it is an
+    /// orphan too, the first ancestor we do not have
+    pub fn missing_ancestor(&self, parent: &H256) -> H256 {
+        let mut hash = *parent;
+        // bounded by the pool size, in case of a loop of bogus parents
+        for _ in 0..=self.orphans.len() {
+            match self.orphans.get(&hash) {
+                Some(orphan) => hash = orphan.block.get_parent(),
+                None => break,
+            }
+        }
+        hash
+    }
+
+    pub fn status(&self) -> OrphanPoolStatus {
+        let now = Instant::now();
+        let mut per_peer = HashMap::new();
+        let mut orphans: Vec<(Instant, OrphanInfo)> = Vec::new();
+        for (hash, orphan) in self.orphans.iter() {
+            *per_peer.entry(orphan.peer).or_default() += 1;
+            orphans.push((orphan.received, OrphanInfo {
+                hash: hash.to_string(),
+                parent: orphan.block.get_parent().to_string(),
+                peer: orphan.peer,
+                age_secs: now.duration_since(orphan.received).as_secs(),
+            }));
+        }
+        orphans.sort_by_key(|(received, _)| *received);
+        OrphanPoolStatus {
+            count: self.orphans.len(),
+            limit: MAX_ORPHANS,
+            per_peer_limit: MAX_ORPHANS_PER_PEER,
+            expiry_secs: ORPHAN_EXPIRY.as_secs(),
+            per_peer,
+            orphans: orphans.into_iter().map(|(_, info)| info).collect(),
+        }
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some(orphan) = self.orphans.remove(hash) {
+            let parent = orphan.block.get_parent();
+            if let Some(siblings) = self.children.get_mut(&parent) {
+                siblings.retain(|sibling| sibling != hash);
+                if siblings.is_empty() {
+                    self.children.remove(&parent);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn resolve_descendants() {
+        let mut pool = OrphanPool::new();
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let missing = H256::from([1; 32]);
+        let child = generate_random_block(&missing);
+        let grandchild = generate_random_block(&child.hash());
+        let sibling = generate_random_block(&child.hash());
+        pool.insert(grandchild.clone(), peer).unwrap();
+        pool.insert(sibling.clone(), peer).unwrap();
+        pool.insert(child.clone(), peer).unwrap();
+        assert_eq!(pool.insert(child.clone(), peer), Err(OrphanError::Duplicate));
+        // the grandchild waits for the block its parent waits for
+        assert_eq!(pool.missing_ancestor(&grandchild.get_parent()), missing);
+        assert_eq!(pool.status().per_peer[&peer], 3);
+
+        assert_eq!(pool.take_children(&missing).len(), 1);
+        assert_eq!(pool.take_children(&child.hash()).len(), 2);
+        assert_eq!(pool.len(), 0);
+
+        // an invalid block takes its descendants with it
+        pool.insert(child.clone(), peer).unwrap();
+        pool.insert(grandchild, peer).unwrap();
+        pool.insert(generate_random_block(&missing), peer).unwrap();
+        assert_eq!(pool.take_children(&missing).len(), 2);
+        assert_eq!(pool.remove_descendants(&child.hash()), 1);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn peer_quota_and_limit() {
+        let mut pool = OrphanPool::new();
+        let greedy: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        for _ in 0..MAX_ORPHANS_PER_PEER {
+            pool.insert(generate_random_block(&H256::from([1; 32])), greedy).unwrap();
+        }
+        let refused = pool.insert(generate_random_block(&H256::from([1; 32])), greedy);
+        assert_eq!(refused, Err(OrphanError::PeerQuota));
+
+        // a full pool makes room by dropping the oldest orphan
+        let first = pool.status().orphans[0].hash.clone();
+        for i in 0..(MAX_ORPHANS - MAX_ORPHANS_PER_PEER + 1) {
+            let peer = SocketAddr::from(([127, 0, 0, 2], (i % 1000) as u16 + 7000));
+            pool.insert(generate_random_block(&H256::from([2; 32])), peer).unwrap();
+        }
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(pool.status().orphans.iter().all(|orphan| orphan.hash != first));
+    }
+}
@@ -1,30 +1,116 @@
-use super::message::Message;
+use super::inventory::PeerInventory;
+use super::message::{Message, Version};
+use crate::types::hash::H256;
 use futures::{channel::mpsc, sink::SinkExt};
 use log::trace;
+use serde::{Deserialize, Serialize};
 use smol::Async;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Identity of a node: the public half of its ed25519 node key, sent in the Version
+#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Copy, Hash, Default, PartialOrd, Ord)]
+pub struct NodeId([u8; 32]);
+
+impl NodeId {
+    pub fn from_public_key_bytes(bytes: &[u8]) -> NodeId {
+        let mut buffer = [0u8; 32];
+        buffer.copy_from_slice(&bytes[..32]);
+        NodeId(buffer)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NodeId, String> {
+        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|e| e.to_string())?;
+        if bytes.len() != 32 {
+            return Err(format!("node id must be 32 bytes, got {}", bytes.len()));
+        }
+        Ok(NodeId::from_public_key_bytes(&bytes))
+    }
+}
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // the first bytes are enough to tell nodes apart in logs
+        write!(f, "{}..", hex::encode(&self.0[..4]))
+    }
+}
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
+    direction: Direction,
 ) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
     let (write_sender, write_receiver) = mpsc::unbounded();
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        status: Arc::new(Mutex::new(Status::default())),
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
+/// What we learnt about a peer during the handshake and since, shared by all clones of its handle
+#[derive(Debug, Default)]
+pub struct Status {
+    pub version: Option<Version>, // Their Version, once we accepted it
+    pub acknowledged: bool, // Whether they accepted our Version and proved their node ID
+    pub challenge: H256, // Sent in our Version, for them to sign
+    pub ack_signature: Option<Vec<u8>>, // From their VerAck, until we check it against their Version
+    pub transport_id: Option<NodeId>, // Node ID proven by the encrypted transport, if it is
+    pub pending_ping: Option<(String, Instant)>, // Nonce of the keepalive ping awaiting a pong
+    pub missed_pongs: u32, // Keepalive pings in a row that went unanswered
+    pub latency: Latency,
+    pub inventory: PeerInventory,
+    pub headers_requested: u32, // GetHeaders still awaiting the peer's Headers
+}
+
+/// Round-trip times of the keepalive pings, in milliseconds
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Latency {
+    pub last_ms: Option<f64>,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub samples: u64,
+}
+
+impl Latency {
+    fn add(&mut self, rtt: Duration) {
+        let ms = rtt.as_secs_f64() * 1000.0;
+        self.samples += 1;
+        self.last_ms = Some(ms);
+        self.min_ms = Some(self.min_ms.map_or(ms, |min| min.min(ms)));
+        let avg = self.avg_ms.unwrap_or(0.0);
+        self.avg_ms = Some(avg + (ms - avg) / self.samples as f64);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    direction: Direction,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    status: Arc<Mutex<Status>>,
 }
 
 #[cfg(any(test,test_utilities))]
@@ -42,16 +128,183 @@ impl Handle {
         });
     }
 
+    /// Drop the connection once the messages already queued are written
+    pub fn disconnect(&mut self) {
+        self.write_queue.close_channel();
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.write_queue.is_closed()
+    }
+
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Address the peer accepts connections on: the one we dialed for outgoing peers, the one it
+    /// announced in its Version for incoming ones
+    pub fn listen_addr(&self) -> Option<std::net::SocketAddr> {
+        match self.direction {
+            Direction::Outgoing => Some(self.addr),
+            Direction::Incoming => self.status().version.as_ref().map(|v| {
+                let mut addr = v.listen_addr;
+                // a node listening on all interfaces is reachable where it connected from
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(self.addr.ip());
+                }
+                addr
+            }),
+        }
+    }
+
+    /// The node ID the peer announced in its Version, once we accepted it
+    pub fn node_id(&self) -> Option<NodeId> {
+        self.status().version.as_ref().map(|v| v.node_id)
+    }
+
+    /// Remember that the peer has these blocks or transactions, so that we do not announce them
+    pub fn mark_known<'a>(&self, hashes: impl IntoIterator<Item = &'a H256>) {
+        let mut status = self.status();
+        for hash in hashes {
+            status.inventory.mark_known(*hash);
+        }
+    }
+
+    /// Ask the peer for blocks, remembering the request so that the blocks are expected
+    pub fn request_blocks(&mut self, hashes: Vec<H256>) {
+        {
+            let mut status = self.status();
+            for hash in hashes.iter() {
+                status.inventory.mark_requested(*hash);
+            }
+        }
+        self.write(Message::GetBlocks(hashes));
+    }
+
+    /// Ask the peer for the headers following `locator`
+    pub fn request_headers(&mut self, locator: Vec<H256>) {
+        self.status().headers_requested += 1;
+        self.write(Message::GetHeaders(locator));
+    }
+
+    /// Count the blocks among `hashes` we did not ask the peer for, forgetting the requests for
+    /// the others
+    pub fn take_unrequested_blocks<'a>(&self, hashes: impl IntoIterator<Item = &'a H256>) -> usize {
+        let mut status = self.status();
+        hashes.into_iter().filter(|hash| !status.inventory.take_requested(hash)).count()
+    }
+
+    /// Whether we were waiting for headers from the peer, counting them as received. Requests
+    /// may overlap, e.g. when two workers both see the handshake complete.
+    pub fn take_headers_request(&self) -> bool {
+        let mut status = self.status();
+        if status.headers_requested == 0 {
+            return false;
+        }
+        status.headers_requested -= 1;
+        true
+    }
+
+    /// Record a keepalive ping with `nonce` about to be sent, returning how many pings in a row,
+    /// counting the one still pending, went unanswered
+    pub fn start_ping(&self, nonce: String) -> u32 {
+        let mut status = self.status();
+        if status.pending_ping.is_some() {
+            status.missed_pongs += 1;
+        }
+        status.pending_ping = Some((nonce, Instant::now()));
+        status.missed_pongs
+    }
+
+    /// Match a pong against the pending keepalive ping, returning the round-trip time if it
+    /// answers it
+    pub fn pong(&self, nonce: &str) -> Option<Duration> {
+        let mut status = self.status();
+        match status.pending_ping.take() {
+            Some((pending, sent)) if pending == nonce => {
+                let rtt = sent.elapsed();
+                status.missed_pongs = 0;
+                status.latency.add(rtt);
+                Some(rtt)
+            }
+            other => {
+                status.pending_ping = other;
+                None
+            }
+        }
+    }
+
+    pub fn status(&self) -> std::sync::MutexGuard<'_, Status> {
+        self.status.lock().unwrap()
+    }
+
+    /// A peer may send us anything once we accepted its Version; the handshake completes when it
+    /// accepted ours too, and only then do we relay to it
+    pub fn version_accepted(&self) -> bool {
+        self.status().version.is_some()
+    }
+
+    pub fn is_handshaken(&self) -> bool {
+        let status = self.status();
+        status.version.is_some() && status.acknowledged
+    }
+
+    /// The node ID from their Version, our challenge and their signature of it, once we have both
+    /// their Version and their VerAck. Given out once, as two workers may handle the two messages.
+    pub fn take_handshake_proof(&self) -> Option<(NodeId, H256, Vec<u8>)> {
+        let mut status = self.status();
+        let node_id = status.version.as_ref()?.node_id;
+        let signature = status.ack_signature.take()?;
+        Some((node_id, status.challenge, signature))
+    }
+
+    /// A handle to a peer that has completed the handshake
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
+        Self::test_handle_at(std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321))
+    }
+
+    /// A handle to a peer at `addr` that has completed the handshake
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_at(addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
+        let (mut handle, r) = Self::test_handle_pending();
+        handle.addr = addr;
+        {
+            let mut status = handle.status();
+            status.version = Some(Version {
+                version: super::message::PROTOCOL_VERSION,
+                genesis: Default::default(),
+                params: Default::default(),
+                best_height: 0,
+                nonce: 0,
+                listen_addr: addr,
+                node_id: Default::default(),
+                challenge: Default::default(),
+            });
+            status.acknowledged = true;
+        }
+        (handle, r)
+    }
+
+    /// A handle to a peer that has just connected
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_pending() -> (Handle, TestReceiver) {
+        Self::test_handle_pending_at(std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321))
+    }
+
+    /// A handle to a peer at `addr` that has just connected
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_pending_at(addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            addr,
+            direction: Direction::Incoming,
             write_queue: s,
+            status: Arc::new(Mutex::new(Status::default())),
         },
         TestReceiver {
             r
@@ -66,4 +319,51 @@ impl TestReceiver {
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
-}
\ No newline at end of file
+
+    /// Wait until the next message, returning `None` if we disconnected from the peer instead
+    pub fn recv_or_disconnect(&mut self) -> Option<Message> {
+        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r))?;
+        Some(bincode::deserialize(&bytes).unwrap())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_id_from_key() {
+        let key = crate::types::key_pair::random();
+        let id = NodeId::from_public_key_bytes(ring::signature::KeyPair::public_key(&key).as_ref());
+        assert_eq!(id.to_string().parse::<NodeId>().unwrap(), id);
+        assert!("00ff".parse::<NodeId>().is_err());
+    }
+
+    #[test]
+    fn ping_latency() {
+        let (peer, _receiver) = Handle::test_handle();
+        assert_eq!(peer.start_ping("1".to_string()), 0);
+        assert!(peer.pong("Test ping").is_none());
+        assert!(peer.pong("1").is_some());
+        assert!(peer.pong("1").is_none());
+        assert_eq!(peer.status().latency.samples, 1);
+
+        // a ping left unanswered counts as missed once the next one goes out
+        peer.start_ping("2".to_string());
+        assert_eq!(peer.start_ping("3".to_string()), 1);
+        assert!(peer.pong("2").is_none());
+        assert!(peer.pong("3").is_some());
+        assert_eq!(peer.status().missed_pongs, 0);
+    }
+
+    #[test]
+    fn overlapping_header_requests() {
+        let (mut peer, _receiver) = Handle::test_handle();
+        assert!(!peer.take_headers_request());
+        // e.g. both the Version and the VerAck completed the handshake
+        peer.request_headers(vec![]);
+        peer.request_headers(vec![]);
+        assert!(peer.take_headers_request());
+        assert!(peer.take_headers_request());
+        assert!(!peer.take_headers_request());
+    }
+}
@@ -1,26 +1,71 @@
-use crate::types::address::Address;
-use super::peer;
+use crate::blockchain::Blockchain;
+use super::address_book::AddressBook;
+use super::framing::{self, FrameError};
+use super::transport::{self, Cipher, Encryption, Session};
+use super::misbehavior::{Ban, BanList, Misbehavior, BAN_THRESHOLD};
+use super::peer::{self, NodeId};
 use super::message;
+use crate::types::hash::H256;
 
 use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+/// How often we check the number of outgoing connections and save the address book
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long we wait for a peer from the address book to accept our connection
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often we ping every peer to check that the connection is alive and measure latency
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// How often the block and transaction announcements queued for each peer are sent, in one batch
+const TRICKLE_INTERVAL: Duration = Duration::from_millis(100);
+/// A peer that leaves this many pings in a row unanswered is dropped
+const MAX_MISSED_PONGS: u32 = 3;
+/// Delay before redialing a configured peer that dropped, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between two attempts to reach a configured peer
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How the server keeps up its connections and treats its peers
+pub struct Config {
+    pub target_outbound: usize, // Outgoing connections we try to keep, dialing the address book
+    pub ban_time: u64, // Seconds a misbehaving peer stays banned
+    pub encryption: Encryption,
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    address_book: AddressBook,
+    node_key: Ed25519KeyPair,
+    config: Config,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let nonce = rand::random::<u64>();
+    let node_id = NodeId::from_public_key_bytes(node_key.public_key().as_ref());
+    let node_key = Arc::new(node_key);
+    let address_book = Arc::new(Mutex::new(address_book));
+    let ban_list = Arc::new(Mutex::new(BanList::new()));
+    let connected_nodes = Arc::new(Mutex::new(HashMap::new()));
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
         p2p_addr: addr,  // Store the P2P address
+        nonce,
+        node_id,
+        node_key: Arc::clone(&node_key),
+        address_book: Arc::clone(&address_book),
+        ban_list: Arc::clone(&ban_list),
+        connected_nodes: Arc::clone(&connected_nodes),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
@@ -28,6 +73,19 @@ pub fn new(
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        blockchain,
+        nonce,
+        node_id,
+        node_key,
+        encryption: config.encryption,
+        address_book,
+        target_outbound: config.target_outbound,
+        dialing: HashSet::new(),
+        configured: HashMap::new(),
+        scores: HashMap::new(),
+        ban_list,
+        ban_time: config.ban_time,
+        connected_nodes,
     };
     Ok((ctx, handle))
 }
@@ -38,6 +96,19 @@ pub struct Context {
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>, // Read to tell peers about our chain
+    nonce: u64,
+    node_id: NodeId,
+    node_key: Arc<Ed25519KeyPair>, // Signs the transport handshake
+    encryption: Encryption,
+    address_book: Arc<Mutex<AddressBook>>,
+    target_outbound: usize, // Outgoing connections we try to keep, dialing the address book
+    dialing: HashSet<std::net::SocketAddr>, // Addresses we are connecting to
+    configured: HashMap<std::net::SocketAddr, Redial>, // Peers we reconnect to whenever they drop
+    scores: HashMap<std::net::SocketAddr, u32>, // Misbehavior of the connected peers
+    ban_list: Arc<Mutex<BanList>>,
+    ban_time: u64, // Seconds a misbehaving peer stays banned
+    connected_nodes: Arc<Mutex<HashMap<NodeId, std::net::SocketAddr>>>, // Node ID of each accepted peer
 }
 
 impl Context {
@@ -54,6 +125,9 @@ impl Context {
             self.dispatch_control(ex_clone).await.unwrap();
         })
             .detach();
+        Self::every(&ex, control_chan.clone(), MAINTENANCE_INTERVAL, || ControlSignal::Maintain);
+        Self::every(&ex, control_chan.clone(), PING_INTERVAL, || ControlSignal::Keepalive);
+        Self::every(&ex, control_chan.clone(), TRICKLE_INTERVAL, || ControlSignal::Trickle);
         ex.spawn(async move {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
@@ -62,6 +136,24 @@ impl Context {
         return Ok(());
     }
 
+    /// Send the signal made by `signal` every `interval`, until the server stops
+    fn every(
+        ex: &Executor<'_>,
+        control_chan: smol::channel::Sender<ControlSignal>,
+        interval: Duration,
+        signal: fn() -> ControlSignal,
+    ) {
+        ex.spawn(async move {
+            loop {
+                smol::Timer::after(interval).await;
+                if control_chan.send(signal()).await.is_err() {
+                    break;
+                }
+            }
+        })
+            .detach();
+    }
+
     /// the loop that endlessly accept incoming peers
     async fn listener_loop(
         listener: Async<net::TcpListener>,
@@ -89,20 +181,143 @@ impl Context {
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
                     for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                        if !hd.is_handshaken() {
+                            continue;
+                        }
+                        // announcements wait for the next trickle, and skip peers that have the data
+                        match &msg {
+                            message::Message::NewBlockHashes(hashes) => {
+                                let mut status = hd.status();
+                                for hash in hashes {
+                                    status.inventory.queue_block(*hash);
+                                }
+                            }
+                            message::Message::NewTransactionHashes(hashes) => {
+                                let mut status = hd.status();
+                                for hash in hashes {
+                                    status.inventory.queue_transaction(*hash);
+                                }
+                            }
+                            _ => hd.write(msg.clone()),
+                        }
+                    }
+                }
+                ControlSignal::Trickle => {
+                    for (_, hd) in self.peers.iter_mut() {
+                        let (blocks, transactions) = hd.status().inventory.take();
+                        for batch in blocks.chunks(message::MAX_INVENTORY) {
+                            hd.write(message::Message::NewBlockHashes(batch.to_vec()));
+                        }
+                        for batch in transactions.chunks(message::MAX_INVENTORY) {
+                            hd.write(message::Message::NewTransactionHashes(batch.to_vec()));
+                        }
                     }
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    if let Err(e) = self.accept(stream, ex.clone()).await {
+                        debug!("Refused incoming peer: {}", e);
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
+                    let handshaken = self.peers.remove(&addr).is_some_and(|peer| peer.is_handshaken());
+                    self.connected_nodes.lock().unwrap().retain(|_, peer| *peer != addr);
+                    self.scores.remove(&addr);
                     info!("Peer {} disconnected", addr);
+                    if let Some(redial) = self.configured.get_mut(&addr) {
+                        // a peer dropping right after connecting is as good as a failed dial
+                        if handshaken {
+                            redial.consecutive_failures = 0;
+                        } else {
+                            redial.failures += 1;
+                            redial.consecutive_failures += 1;
+                            redial.last_error = Some("disconnected before the handshake".to_string());
+                        }
+                        if let Some(delay) = self.schedule_redial(addr, ex.clone()) {
+                            info!("Redialing configured peer {} in {:?}", addr, delay);
+                        }
+                    }
+                }
+                ControlSignal::AddConfiguredPeer(addr) => {
+                    trace!("Processing AddConfiguredPeer({})", addr);
+                    self.configured.entry(addr).or_default();
+                    self.redial(addr, ex.clone());
+                }
+                ControlSignal::Redial(addr) => {
+                    trace!("Processing Redial({})", addr);
+                    self.redial(addr, ex.clone());
+                }
+                ControlSignal::ListConfiguredPeers(result_chan) => {
+                    trace!("Processing ListConfiguredPeers command");
+                    let now = Instant::now();
+                    let mut peers: Vec<ConfiguredPeerInfo> = self.configured.iter()
+                        .map(|(addr, redial)| ConfiguredPeerInfo {
+                            addr: *addr,
+                            connected: self.peers.get(addr).is_some_and(|peer| peer.is_connected()),
+                            attempts: redial.attempts,
+                            failures: redial.failures,
+                            consecutive_failures: redial.consecutive_failures,
+                            last_error: redial.last_error.clone(),
+                            retry_in_ms: redial.next_attempt
+                                .map(|at| at.saturating_duration_since(now).as_millis() as u64),
+                        })
+                        .collect();
+                    peers.sort_by_key(|peer| peer.addr);
+                    let _ = result_chan.send(peers);
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::Misbehaving(peer, misbehavior) => {
+                    trace!("Processing Misbehaving({}, {})", peer.addr(), misbehavior);
+                    self.misbehaving(peer, misbehavior);
+                }
+                ControlSignal::Ban(ip, duration) => {
+                    trace!("Processing Ban({})", ip);
+                    let duration = duration.unwrap_or(self.ban_time);
+                    self.ban(ip, duration, "banned through the API");
+                }
+                ControlSignal::SendToPeer((node_id, msg)) => {
+                    trace!("Processing SendToPeer command");
+                    match self.peers.values_mut().find(|peer| peer.is_handshaken() && peer.node_id() == Some(node_id)) {
+                        Some(peer) => peer.write(msg),
+                        None => debug!("Not connected to node {}, dropping message", node_id),
+                    }
+                }
+                ControlSignal::ListPeers(result_chan) => {
+                    trace!("Processing ListPeers command");
+                    let mut peers: Vec<PeerInfo> = self.peers.values()
+                        .filter(|peer| peer.is_connected())
+                        .map(PeerInfo::new)
+                        .collect();
+                    peers.sort_by_key(|peer| peer.addr);
+                    let _ = result_chan.send(peers);
+                }
+                ControlSignal::Keepalive => {
+                    trace!("Processing Keepalive command");
+                    self.keepalive();
+                }
+                ControlSignal::Maintain => {
+                    trace!("Processing Maintain command");
+                    self.maintain(ex.clone());
+                }
+                ControlSignal::Dialed(addr, result) => {
+                    trace!("Processing Dialed({})", addr);
+                    self.dialing.remove(&addr);
+                    let result = match result {
+                        Ok(stream) => self.register(stream, peer::Direction::Outgoing, ex.clone()).await,
+                        Err(e) => Err(e),
+                    };
+                    match (result, self.configured.get_mut(&addr)) {
+                        (Ok(_), _) => info!("Connected to peer {}", addr),
+                        (Err(e), None) => debug!("Error connecting to peer {} from the address book: {}", addr, e),
+                        (Err(e), Some(redial)) => {
+                            redial.failures += 1;
+                            redial.consecutive_failures += 1;
+                            redial.last_error = Some(e.to_string());
+                            if let Some(delay) = self.schedule_redial(addr, ex.clone()) {
+                                warn!("Error connecting to configured peer {}, retrying in {:?}: {}", addr, delay, e);
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -131,91 +346,190 @@ impl Context {
         Ok(())
     }
 
+    /// Dial addresses from the address book until we have `target_outbound` outgoing
+    /// connections, and save the book
+    fn maintain(&mut self, ex: Arc<Executor<'_>>) {
+        self.peers.retain(|_, peer| peer.is_connected());
+        let outbound = self.peers.values()
+            .filter(|peer| peer.direction() == peer::Direction::Outgoing)
+            .count();
+        let mut exclude: HashSet<std::net::SocketAddr> = self.peers.values()
+            .filter_map(|peer| peer.listen_addr())
+            .collect();
+        exclude.insert(self.addr);
+        exclude.extend(self.dialing.iter().cloned());
+        let banned: HashSet<std::net::IpAddr> = self.ban_list.lock().unwrap().list().into_iter().map(|ban| ban.ip).collect();
+
+        let missing = self.target_outbound.saturating_sub(outbound + self.dialing.len());
+        let chosen: Vec<std::net::SocketAddr> = {
+            let mut book = self.address_book.lock().unwrap();
+            let chosen: Vec<std::net::SocketAddr> = book.candidates(&exclude)
+                .into_iter()
+                .filter(|addr| !banned.contains(&addr.ip()))
+                .take(missing)
+                .collect();
+            for addr in chosen.iter() {
+                book.mark_attempt(*addr);
+            }
+            if let Err(e) = book.save() {
+                warn!("Error saving the address book: {}", e);
+            }
+            chosen
+        };
+        for addr in chosen {
+            debug!("Dialing {} from the address book", addr);
+            self.dial(addr, ex.clone());
+        }
+    }
+
+    /// Connect to `addr` in the background; the outcome comes back as a `Dialed` signal
+    fn dial(&mut self, addr: std::net::SocketAddr, ex: Arc<Executor<'_>>) {
+        self.dialing.insert(addr);
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            let result = smol::future::or(
+                Async::<net::TcpStream>::connect(addr),
+                async {
+                    smol::Timer::after(DIAL_TIMEOUT).await;
+                    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out"))
+                },
+            ).await;
+            let _ = control_chan.send(ControlSignal::Dialed(addr, result)).await;
+        })
+            .detach();
+    }
+
+    /// Dial a configured peer, unless it is connected, being dialed or banned
+    fn redial(&mut self, addr: std::net::SocketAddr, ex: Arc<Executor<'_>>) {
+        let connected = self.peers.get(&addr).is_some_and(|peer| peer.is_connected());
+        let banned = self.ban_list.lock().unwrap().is_banned(&addr.ip());
+        let redial = match self.configured.get_mut(&addr) {
+            Some(redial) => redial,
+            None => return,
+        };
+        redial.next_attempt = None;
+        if connected || self.dialing.contains(&addr) {
+            return;
+        }
+        if banned {
+            info!("Not redialing configured peer {}, it is banned", addr);
+            return;
+        }
+        redial.attempts += 1;
+        info!("Dialing configured peer {} (attempt {})", addr, redial.attempts);
+        self.dial(addr, ex);
+    }
+
+    /// Send a `Redial` for a configured peer once its backoff elapses, returning the delay, or
+    /// `None` if one is already scheduled
+    fn schedule_redial(&mut self, addr: std::net::SocketAddr, ex: Arc<Executor<'_>>) -> Option<Duration> {
+        let redial = self.configured.get_mut(&addr)?;
+        if redial.next_attempt.is_some() {
+            return None;
+        }
+        let delay = backoff(redial.consecutive_failures);
+        redial.next_attempt = Some(Instant::now() + delay);
+        let control_chan = self.control_sender.clone();
+        ex.spawn(async move {
+            smol::Timer::after(delay).await;
+            let _ = control_chan.send(ControlSignal::Redial(addr)).await;
+        })
+            .detach();
+        Some(delay)
+    }
+
+    /// Ping every handshaken peer, dropping the ones that stopped answering
+    fn keepalive(&mut self) {
+        for peer in self.peers.values_mut().filter(|peer| peer.is_handshaken()) {
+            let nonce = rand::random::<u64>().to_string();
+            let missed = peer.start_ping(nonce.clone());
+            if missed >= MAX_MISSED_PONGS {
+                info!("Peer {} missed {} pings, disconnecting", peer.addr(), missed);
+                peer.disconnect();
+            } else {
+                peer.write(message::Message::Ping(nonce));
+            }
+        }
+    }
+
+    /// Add to the score of a connection, and ban the IP of the peer once the score reaches the
+    /// threshold. Loopback peers are only disconnected, as every local node shares their IP.
+    fn misbehaving(&mut self, mut peer: peer::Handle, misbehavior: Misbehavior) {
+        let score = self.scores.entry(*peer.addr()).or_default();
+        *score += misbehavior.score();
+        warn!("Peer {} misbehaved ({}), score {}", peer.addr(), misbehavior, score);
+        if *score < BAN_THRESHOLD {
+            return;
+        }
+        let ip = peer.addr().ip();
+        if ip.is_loopback() {
+            info!("Disconnecting local peer {}, not banning its IP: {}", peer.addr(), misbehavior);
+        } else {
+            self.ban(ip, self.ban_time, &misbehavior.to_string());
+        }
+        peer.disconnect();
+    }
+
+    /// Ban `ip` for `duration` seconds and drop the peers connected from it
+    fn ban(&mut self, ip: std::net::IpAddr, duration: u64, reason: &str) {
+        info!("Banning {} for {} seconds: {}", ip, duration, reason);
+        self.ban_list.lock().unwrap().ban(ip, duration, reason);
+        for peer in self.peers.values_mut() {
+            if peer.addr().ip() == ip {
+                peer.disconnect();
+            }
+        }
+    }
+
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (write_queue, handle) = peer::new(&stream, direction)?;
+        if self.ban_list.lock().unwrap().is_banned(&handle.addr().ip()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("peer {} is banned", handle.addr()),
+            ));
+        }
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
-        let handle_copy = handle.clone();
+        let mut handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        let node_key = Arc::clone(&self.node_key);
+        let encryption = self.encryption;
 
-        // start the reactor for this peer
-        // first, start a task that keeps reading from this guy
-        let mut reader = BufReader::new(stream.clone());
+        // start the reactor for this peer: set up the transport, then keep reading from and
+        // writing to this guy
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
-            let mut msg_buffer: Vec<u8> = vec![];
-            loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
-                        break;
-                    }
-                };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
-                    .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
-                    }
-                    Err(_) => {
-                        break;
-                    }
-                }
-            }
-            // the peer is disconnected
-        })
-            .detach();
-
-        // second, start a task that keeps writing to this guy
-        let mut writer = BufWriter::new(stream.clone());
-        ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
-                }
-                match writer.write_all(&new_msg).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
+            let mut reader = BufReader::new(stream.clone());
+            let mut writer = BufWriter::new(stream.clone());
+            match transport::handshake(&mut reader, &mut writer, encryption, &node_key, direction).await {
+                Ok(session) => {
+                    let (sealer, opener, first_frame) = match session {
+                        Session::Encrypted { sealer, opener, node_id } => {
+                            debug!("Encrypted connection with {}, node {:?}", addr, node_id);
+                            handle_copy.status().transport_id = Some(node_id);
+                            (Some(sealer), Some(opener), None)
+                        }
+                        Session::Plain { first_frame } => (None, None, first_frame),
+                    };
+                    smol::future::zip(
+                        Self::read_loop(reader, opener, first_frame, handle_copy, new_msg_chan, control_chan.clone()),
+                        Self::write_loop(writer, sealer, write_queue),
+                    ).await;
                 }
-                match writer.flush().await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
+                Err(e) => {
+                    info!("Transport handshake with {} failed: {}", addr, e);
+                    handle_copy.disconnect();
                 }
             }
             // the peer is disconnected
+            let _ = stream.get_ref().shutdown(net::Shutdown::Both);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -223,16 +537,173 @@ impl Context {
         })
             .detach();
 
+        // introduce ourselves, the peer is ignored until it does the same
+        let mut handle = handle;
+        let version = self.version();
+        handle.status().challenge = version.challenge;
+        handle.write(message::Message::Version(version));
+
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
         Ok(handle)
     }
+
+    /// Pass the frames of a peer on to the workers, decrypting them if the transport is encrypted
+    async fn read_loop(
+        mut reader: BufReader<AsyncArc<Async<net::TcpStream>>>,
+        mut opener: Option<Cipher>,
+        first_frame: Option<Vec<u8>>,
+        mut handle: peer::Handle,
+        new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+        control_chan: smol::channel::Sender<ControlSignal>,
+    ) {
+        let mut pending = first_frame;
+        let mut first = true;
+        loop {
+            let frame = match pending.take() {
+                Some(frame) => Ok(frame),
+                None => framing::read_frame(&mut reader).await,
+            };
+            let frame = match (frame, opener.as_mut()) {
+                (Ok(frame), Some(opener)) => opener.open(frame),
+                (frame, _) => frame,
+            };
+            match frame {
+                // a peer that would like to encrypt starts with its hello, which we leave unanswered
+                Ok(payload) if first && opener.is_none() && transport::is_hello(&payload) => {}
+                Ok(payload) => {
+                    new_msg_chan
+                        .send((payload, handle.clone()))
+                        .await
+                        .unwrap();
+                }
+                // the peer is disconnected
+                Err(FrameError::Io(_)) => break,
+                // we cannot find the next frame, drop the peer
+                Err(e) => {
+                    warn!("Dropping peer {}: {}", handle.addr(), e);
+                    if let Some(misbehavior) = e.misbehavior() {
+                        let _ = control_chan
+                            .send(ControlSignal::Misbehaving(handle.clone(), misbehavior))
+                            .await;
+                    }
+                    break;
+                }
+            }
+            first = false;
+        }
+        // stop the writer too
+        handle.disconnect();
+    }
+
+    /// Write the messages queued for a peer, encrypting them if the transport is encrypted
+    async fn write_loop(
+        mut writer: BufWriter<AsyncArc<Async<net::TcpStream>>>,
+        mut sealer: Option<Cipher>,
+        mut write_queue: futures::channel::mpsc::UnboundedReceiver<Vec<u8>>,
+    ) {
+        // the queue closes when we drop the peer
+        while let Some(payload) = write_queue.next().await {
+            let frame = match sealer.as_mut() {
+                Some(sealer) => sealer.seal(&payload),
+                None => payload,
+            };
+            if framing::write_frame(&mut writer, &frame).await.is_err() {
+                break;
+            }
+        }
+        // make sure the reader stops as well
+        let _ = writer.get_ref().get_ref().shutdown(net::Shutdown::Both);
+    }
+
+    /// Our Version for a new connection, with a fresh challenge
+    fn version(&self) -> message::Version {
+        let blockchain = self.blockchain.lock().unwrap();
+        message::Version {
+            version: message::PROTOCOL_VERSION,
+            genesis: blockchain.genesis_hash(),
+            params: blockchain.params().digest(),
+            best_height: blockchain.height(&blockchain.tip()).unwrap_or(0) as u64,
+            nonce: self.nonce,
+            listen_addr: self.addr,
+            node_id: self.node_id,
+            challenge: H256::from(rand::random::<[u8; 32]>()),
+        }
+    }
+}
+
+/// Delay before the next attempt to reach a configured peer after `failures` failed attempts in a
+/// row
+fn backoff(failures: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(1 << failures.min(16))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+/// How we are doing at staying connected to a peer given on the command line
+#[derive(Debug, Default)]
+struct Redial {
+    attempts: u64,
+    failures: u64, // Failed dials, and connections dropped before the handshake
+    consecutive_failures: u32, // Failures since the last connection that completed the handshake
+    last_error: Option<String>,
+    next_attempt: Option<Instant>, // When the scheduled redial fires, if there is one
+}
+
+/// A configured peer, as listed through the API
+#[derive(Serialize, Debug, Clone)]
+pub struct ConfiguredPeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub connected: bool,
+    pub attempts: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub retry_in_ms: Option<u64>,
+}
+
+/// A connected peer, as listed through the API
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    pub listen_addr: Option<std::net::SocketAddr>,
+    pub node_id: Option<String>, // Hex, known once we accepted the peer's Version
+    pub handshaken: bool,
+    pub encrypted: bool,
+    pub latency: peer::Latency,
+    pub missed_pongs: u32,
+}
+
+impl PeerInfo {
+    fn new(peer: &peer::Handle) -> Self {
+        let (encrypted, latency, missed_pongs) = {
+            let status = peer.status();
+            (status.transport_id.is_some(), status.latency.clone(), status.missed_pongs)
+        };
+        PeerInfo {
+            addr: *peer.addr(),
+            direction: peer.direction(),
+            listen_addr: peer.listen_addr(),
+            node_id: peer.node_id().map(|id| id.to_string()),
+            handshaken: peer.is_handshaken(),
+            encrypted,
+            latency,
+            missed_pongs,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
     pub p2p_addr: std::net::SocketAddr,
+    pub nonce: u64, // Identifies this node in the version handshake
+    pub node_id: NodeId,
+    node_key: Arc<Ed25519KeyPair>, // Signs the challenges of our peers
+    address_book: Arc<Mutex<AddressBook>>,
+    ban_list: Arc<Mutex<BanList>>,
+    connected_nodes: Arc<Mutex<HashMap<NodeId, std::net::SocketAddr>>>,
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -248,6 +719,15 @@ impl TestReceiver {
             _ => None,
         }
     }
+
+    /// Wait for the next signal, returning the misbehavior if it is a report
+    pub fn recv_report(&self) -> Option<Misbehavior> {
+        let sig = smol::block_on(self.control_chan.recv()).unwrap();
+        match sig {
+            ControlSignal::Misbehaving(_, misbehavior) => Some(misbehavior),
+            _ => None,
+        }
+    }
 }
 
 impl Handle {
@@ -261,18 +741,86 @@ impl Handle {
         smol::block_on(receiver).unwrap()
     }
 
+    pub fn address_book(&self) -> std::sync::MutexGuard<'_, AddressBook> {
+        self.address_book.lock().unwrap()
+    }
+
+    /// Add to the misbehavior score of a peer, which gets it banned past the threshold
+    pub fn report(&self, peer: &peer::Handle, misbehavior: Misbehavior) {
+        smol::block_on(self.control_chan.send(ControlSignal::Misbehaving(peer.clone(), misbehavior))).unwrap();
+    }
+
+    /// Sign the challenge of a peer's Version, proving that we are `node_id`
+    pub fn sign_challenge(&self, challenge: &H256) -> Vec<u8> {
+        message::sign_challenge(&self.node_key, challenge)
+    }
+
+    /// Record that the peer at `addr` is node `node_id`, unless another connection already is,
+    /// returning whether it was recorded
+    pub fn claim_node_id(&self, node_id: NodeId, addr: std::net::SocketAddr) -> bool {
+        let mut connected = self.connected_nodes.lock().unwrap();
+        match connected.get(&node_id) {
+            Some(other) if *other != addr => false,
+            _ => {
+                connected.insert(node_id, addr);
+                true
+            }
+        }
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.ban_list.lock().unwrap().list()
+    }
+
+    /// Ban `ip` for `duration` seconds, or the configured ban time, and drop its connections
+    pub fn ban(&self, ip: std::net::IpAddr, duration: Option<u64>) {
+        smol::block_on(self.control_chan.send(ControlSignal::Ban(ip, duration))).unwrap();
+    }
+
+    pub fn unban(&self, ip: &std::net::IpAddr) -> bool {
+        self.ban_list.lock().unwrap().unban(ip)
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
+    /// Write to the connected node with this ID, if it completed the handshake
+    pub fn send(&self, receiver: NodeId, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Connect to `addr`, and reconnect with exponential backoff whenever the connection drops
+    pub fn add_configured_peer(&self, addr: std::net::SocketAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::AddConfiguredPeer(addr))).unwrap();
+    }
+
+    pub fn configured_peers(&self) -> Vec<ConfiguredPeerInfo> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ListConfiguredPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ListPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let node_key = crate::types::key_pair::random();
+        let h = Handle {
+            control_chan: s,
+            p2p_addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 6000),
+            nonce: rand::random(),
+            node_id: NodeId::from_public_key_bytes(node_key.public_key().as_ref()),
+            node_key: Arc::new(node_key),
+            address_book: Arc::new(Mutex::new(AddressBook::new())),
+            ban_list: Arc::new(Mutex::new(BanList::new())),
+            connected_nodes: Arc::new(Mutex::new(HashMap::new())),
+        };
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -286,5 +834,68 @@ enum ControlSignal {
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    SendToPeer((NodeId,message::Message)),
+    ListPeers(oneshot::Sender<Vec<PeerInfo>>),
+    Maintain,
+    Keepalive,
+    Trickle,
+    AddConfiguredPeer(std::net::SocketAddr),
+    Redial(std::net::SocketAddr),
+    ListConfiguredPeers(oneshot::Sender<Vec<ConfiguredPeerInfo>>),
+    Dialed(std::net::SocketAddr, std::io::Result<Async<net::TcpStream>>),
+    Misbehaving(peer::Handle, Misbehavior),
+    Ban(std::net::IpAddr, Option<u64>), // Duration in seconds, the configured one if None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        assert_eq!(backoff(0), INITIAL_BACKOFF);
+        assert_eq!(backoff(1), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(3), INITIAL_BACKOFF * 8);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn score_connections() {
+        let (msg_sink, _msg_receiver) = smol::channel::unbounded();
+        let (mut ctx, handle) = new(
+            "127.0.0.1:0".parse().unwrap(),
+            msg_sink,
+            Arc::new(Mutex::new(Blockchain::new())),
+            AddressBook::new(),
+            crate::types::key_pair::random(),
+            Config { target_outbound: 0, ban_time: 60, encryption: Encryption::Off },
+        ).unwrap();
+        let mut connect = |addr: &str| {
+            let (peer, receiver) = peer::Handle::test_handle_at(addr.parse().unwrap());
+            ctx.peers.insert(*peer.addr(), peer.clone());
+            (peer, receiver)
+        };
+        let (local, _r1) = connect("127.0.0.1:6001");
+        let (other_local, _r2) = connect("127.0.0.1:6002");
+        let (remote, _r3) = connect("10.0.0.1:6001");
+        let (other_remote, _r4) = connect("10.0.0.1:6002");
+
+        // each connection has its own score
+        ctx.misbehaving(local.clone(), Misbehavior::Unsolicited);
+        ctx.misbehaving(other_local.clone(), Misbehavior::Unsolicited);
+        assert_eq!(ctx.scores[local.addr()], Misbehavior::Unsolicited.score());
+
+        // a local peer is dropped alone, the other nodes on this machine share its IP
+        ctx.misbehaving(local.clone(), Misbehavior::InvalidBlock);
+        assert!(!local.is_connected());
+        assert!(other_local.is_connected());
+        assert!(handle.bans().is_empty());
+
+        // a remote one gets its IP banned, with every connection from it
+        ctx.misbehaving(remote.clone(), Misbehavior::InvalidBlock);
+        assert!(!remote.is_connected());
+        assert!(!other_remote.is_connected());
+        assert_eq!(handle.bans()[0].ip, remote.addr().ip());
+    }
 }
@@ -0,0 +1,163 @@
+use super::peer;
+use crate::types::hash::H256;
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most headers sent in one `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+/// Block bodies requested from a peer in one `GetBlocks` message during the initial download
+pub const BATCH_SIZE: usize = 16;
+/// Batches a single peer may have outstanding at once
+const MAX_BATCHES_PER_PEER: usize = 2;
+/// A batch that has not arrived after this long is asked from another peer
+const BATCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bookkeeping of the headers-first download, shared by the network workers. The headers
+/// themselves are checked and kept by the blockchain; this tracks which bodies are still
+/// needed and who was asked for them.
+#[derive(Default)]
+pub struct SyncState {
+    queue: VecDeque<H256>, // Bodies to download, in chain order
+    queued: HashSet<H256>,
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    peers: HashMap<SocketAddr, peer::Handle>, // Handshaken peers we can download from
+}
+
+/// Progress of the download, as reported through the API
+#[derive(Serialize, Debug, Clone)]
+pub struct SyncStatus {
+    pub syncing: bool,
+    pub tip_height: usize,
+    pub best_header_height: usize,
+    pub queued: usize,
+    pub in_flight: usize,
+}
+
+impl SyncState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_peer(&mut self, peer: &peer::Handle) {
+        self.peers.insert(*peer.addr(), peer.clone());
+    }
+
+    /// Queue the body of a block whose header was just accepted
+    pub fn want(&mut self, hash: H256) {
+        if self.queued.insert(hash) {
+            self.queue.push_back(hash);
+        }
+    }
+
+    /// Forget about a body once it arrived, or once we got the block some other way
+    pub fn received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+        self.queued.remove(hash);
+    }
+
+    /// Hand out queued bodies to peers in batches, spreading them over every peer that still has
+    /// room, and give batches that timed out to someone else
+    pub fn request_bodies(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<H256> = self.in_flight
+            .iter()
+            .filter(|(_, (_, since))| now.duration_since(*since) > BATCH_TIMEOUT)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            let (addr, _) = self.in_flight.remove(&hash).unwrap();
+            debug!("Block {:?} timed out at {}, asking again", hash, addr);
+            self.queue.push_front(hash);
+        }
+        self.peers.retain(|_, peer| peer.is_connected());
+
+        let mut load: HashMap<SocketAddr, usize> = HashMap::new();
+        for (addr, _) in self.in_flight.values() {
+            *load.entry(*addr).or_default() += 1;
+        }
+        let mut progress = true;
+        while progress && !self.queue.is_empty() {
+            progress = false;
+            for (addr, peer) in self.peers.iter_mut() {
+                let outstanding = load.entry(*addr).or_default();
+                if *outstanding >= BATCH_SIZE * MAX_BATCHES_PER_PEER {
+                    continue;
+                }
+                let mut batch = Vec::with_capacity(BATCH_SIZE);
+                while batch.len() < BATCH_SIZE {
+                    match self.queue.pop_front() {
+                        // skip bodies that arrived in the meantime
+                        Some(hash) if self.queued.contains(&hash) => batch.push(hash),
+                        Some(_) => continue,
+                        None => break,
+                    }
+                }
+                if batch.is_empty() {
+                    break;
+                }
+                for hash in batch.iter() {
+                    self.in_flight.insert(*hash, (*addr, now));
+                }
+                *outstanding += batch.len();
+                debug!("Requesting {} blocks from {}", batch.len(), addr);
+                peer.request_blocks(batch);
+                progress = true;
+            }
+        }
+    }
+
+    pub fn status(&self, tip_height: usize, best_header_height: usize) -> SyncStatus {
+        SyncStatus {
+            syncing: best_header_height > tip_height || !self.in_flight.is_empty(),
+            tip_height,
+            best_header_height,
+            queued: self.queued.len() - self.in_flight.len().min(self.queued.len()),
+            in_flight: self.in_flight.len(),
+        }
+    }
+
+    /// Log how far the blocks are behind the best header, if they are
+    pub fn log_progress(&self, tip_height: usize, best_header_height: usize) {
+        if best_header_height > tip_height {
+            info!("Syncing: at height {} of {}, {} blocks in flight",
+                  tip_height, best_header_height, self.in_flight.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::message::Message;
+
+    #[test]
+    fn spread_batches_over_peers() {
+        let mut sync = SyncState::new();
+        let (peer_a, mut receiver_a) = peer::Handle::test_handle_at("127.0.0.1:12321".parse().unwrap());
+        let (peer_b, mut receiver_b) = peer::Handle::test_handle_at("127.0.0.1:12322".parse().unwrap());
+        sync.add_peer(&peer_a);
+        sync.add_peer(&peer_b);
+        for i in 0..(BATCH_SIZE * 3) as u8 {
+            sync.want(H256::from([i; 32]));
+        }
+        sync.request_bodies();
+        let mut requested = 0;
+        for receiver in [&mut receiver_a, &mut receiver_b].iter_mut() {
+            match receiver.recv() {
+                Message::GetBlocks(hashes) => {
+                    assert!(hashes.len() <= BATCH_SIZE);
+                    requested += hashes.len();
+                }
+                _ => panic!(),
+            }
+        }
+        assert!(requested >= BATCH_SIZE * 2);
+        assert_eq!(sync.status(0, 48).in_flight, BATCH_SIZE * 3);
+
+        sync.received(&H256::from([0u8; 32]));
+        assert_eq!(sync.status(0, 48).in_flight, BATCH_SIZE * 3 - 1);
+    }
+}
@@ -0,0 +1,291 @@
+use super::framing::{self, FrameError};
+use super::peer::{Direction, NodeId};
+use futures::io::{AsyncRead, AsyncWrite};
+use ring::{aead, agreement, hkdf, rand, signature};
+use ring::signature::KeyPair;
+use std::time::Duration;
+
+/// Marks the first frame of a node that encrypts; no bincode message starts with these bytes
+const HELLO_MAGIC: &[u8; 4] = b"ENC1";
+/// Magic, ephemeral X25519 key, ed25519 node key
+const HELLO_SIZE: usize = 4 + 32 + 32;
+/// Signed together with the transcript, so that the signature means nothing elsewhere
+const HELLO_CONTEXT: &[u8] = b"bitcoin p2p hello";
+/// How long we wait for the first frame of a peer when we want to encrypt
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether we encrypt the connections to our peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    Off, // Plaintext only; the hello of a peer that would encrypt is skipped
+    On, // Encrypt with peers that do, plaintext with the others
+    Strict, // Refuse peers that do not encrypt
+}
+
+impl std::str::FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encryption, String> {
+        match s {
+            "off" => Ok(Encryption::Off),
+            "on" => Ok(Encryption::On),
+            "strict" => Ok(Encryption::Strict),
+            _ => Err(format!("unknown encryption mode {}, expected off, on or strict", s)),
+        }
+    }
+}
+
+/// Seals or opens the frames going one way, numbering them so that none can be replayed, dropped
+/// or reordered unnoticed
+pub struct Cipher {
+    key: Box<aead::LessSafeKey>, // Boxed, the expanded key is large
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(okm: hkdf::Okm<&'static aead::Algorithm>) -> Self {
+        Cipher {
+            key: Box::new(aead::LessSafeKey::new(aead::UnboundKey::from(okm))),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        // 2^64 frames will not happen on one connection
+        self.counter += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Encrypt a payload into the body of a frame; the length header is authenticated too
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = payload.to_vec();
+        let size = ((payload.len() + aead::CHACHA20_POLY1305.tag_len()) as u32).to_be_bytes();
+        let nonce = self.next_nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, aead::Aad::from(size), &mut frame)
+            .expect("Failed to seal frame");
+        frame
+    }
+
+    pub fn open(&mut self, mut frame: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        let size = (frame.len() as u32).to_be_bytes();
+        let nonce = self.next_nonce();
+        let len = self.key
+            .open_in_place(nonce, aead::Aad::from(size), &mut frame)
+            .map_err(|_| FrameError::Malformed("frame failed authentication".to_string()))?
+            .len();
+        frame.truncate(len);
+        Ok(frame)
+    }
+}
+
+/// What the transport handshake settled on
+pub enum Session {
+    Encrypted {
+        sealer: Cipher,
+        opener: Cipher,
+        node_id: NodeId, // Proven by the peer's signature
+    },
+    Plain {
+        first_frame: Option<Vec<u8>>, // Read while waiting for a hello that never came
+    },
+}
+
+/// Whether a plaintext frame is the hello of a peer that would like to encrypt
+pub fn is_hello(frame: &[u8]) -> bool {
+    frame.len() == HELLO_SIZE && frame.starts_with(HELLO_MAGIC)
+}
+
+/// Set up the transport of a new connection. Both sides speak first: a node that encrypts sends
+/// its hello, a node that does not sends its Version. The hellos carry ephemeral X25519 keys and
+/// the node keys. Each side then signs both hellos and its own direction with its node key, and
+/// the agreed secret keys one cipher per direction.
+pub async fn handshake<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    encryption: Encryption,
+    node_key: &signature::Ed25519KeyPair,
+    direction: Direction,
+) -> Result<Session, String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if encryption == Encryption::Off {
+        return Ok(Session::Plain { first_frame: None });
+    }
+    let rng = rand::SystemRandom::new();
+    let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+        .map_err(|_| "failed to generate an ephemeral key".to_string())?;
+    let ours = hello(&ephemeral, node_key)?;
+    framing::write_frame(writer, &ours).await.map_err(|e| e.to_string())?;
+
+    let theirs = read_frame_timeout(reader, "the first frame").await?;
+    if !is_hello(&theirs) {
+        if encryption == Encryption::Strict {
+            return Err("peer does not encrypt".to_string());
+        }
+        return Ok(Session::Plain { first_frame: Some(theirs) });
+    }
+    let (their_ephemeral, their_key) = (&theirs[4..36], &theirs[36..68]);
+
+    // the transcript, in the same order on both sides
+    let (outgoing, incoming) = match direction {
+        Direction::Outgoing => (&ours, &theirs),
+        Direction::Incoming => (&theirs, &ours),
+    };
+    let our_signature = node_key.sign(&transcript(direction, outgoing, incoming));
+    framing::write_frame(writer, our_signature.as_ref()).await.map_err(|e| e.to_string())?;
+    let their_signature = read_frame_timeout(reader, "the hello signature").await?;
+    let their_direction = match direction {
+        Direction::Outgoing => Direction::Incoming,
+        Direction::Incoming => Direction::Outgoing,
+    };
+    signature::UnparsedPublicKey::new(&signature::ED25519, their_key)
+        .verify(&transcript(their_direction, outgoing, incoming), &their_signature)
+        .map_err(|_| "invalid hello signature".to_string())?;
+    let mut salt = outgoing[4..].to_vec();
+    salt.extend_from_slice(&incoming[4..]);
+    let (to_incoming, to_outgoing) = agreement::agree_ephemeral(
+        ephemeral,
+        &agreement::UnparsedPublicKey::new(&agreement::X25519, their_ephemeral),
+        ring::error::Unspecified,
+        |secret| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(secret);
+            let to_incoming = Cipher::new(prk.expand(&[b"to incoming"], &aead::CHACHA20_POLY1305)?);
+            let to_outgoing = Cipher::new(prk.expand(&[b"to outgoing"], &aead::CHACHA20_POLY1305)?);
+            Ok((to_incoming, to_outgoing))
+        },
+    ).map_err(|_| "key agreement failed".to_string())?;
+    let (sealer, opener) = match direction {
+        Direction::Outgoing => (to_incoming, to_outgoing),
+        Direction::Incoming => (to_outgoing, to_incoming),
+    };
+    Ok(Session::Encrypted {
+        sealer,
+        opener,
+        node_id: NodeId::from_public_key_bytes(their_key),
+    })
+}
+
+async fn read_frame_timeout<R: AsyncRead + Unpin>(reader: &mut R, what: &str) -> Result<Vec<u8>, String> {
+    smol::future::or(
+        async { framing::read_frame(reader).await.map_err(|e| e.to_string()) },
+        async {
+            smol::Timer::after(HANDSHAKE_TIMEOUT).await;
+            Err(format!("timed out waiting for {}", what))
+        },
+    ).await
+}
+
+fn hello(
+    ephemeral: &agreement::EphemeralPrivateKey,
+    node_key: &signature::Ed25519KeyPair,
+) -> Result<Vec<u8>, String> {
+    let public = ephemeral.compute_public_key()
+        .map_err(|_| "failed to compute the ephemeral public key".to_string())?;
+    let mut hello = HELLO_MAGIC.to_vec();
+    hello.extend_from_slice(public.as_ref());
+    hello.extend_from_slice(node_key.public_key().as_ref());
+    Ok(hello)
+}
+
+/// What the node on the `signer` side of the connection signs: both hellos and its direction, so
+/// that the signature is only good for this handshake, and only coming from that side
+fn transcript(signer: Direction, outgoing: &[u8], incoming: &[u8]) -> Vec<u8> {
+    let mut signed = HELLO_CONTEXT.to_vec();
+    signed.extend_from_slice(match signer {
+        Direction::Outgoing => b"outgoing",
+        Direction::Incoming => b"incoming",
+    });
+    signed.extend_from_slice(&outgoing[4..]);
+    signed.extend_from_slice(&incoming[4..]);
+    signed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    /// Run the handshake on both ends of a loopback connection
+    fn connect(ours: Encryption, theirs: Encryption) -> (Result<Session, String>, Result<Session, String>) {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        smol::block_on(async {
+            let outgoing = Async::<TcpStream>::connect(addr).await.unwrap();
+            let (incoming, _) = listener.accept().await.unwrap();
+            let (our_key, their_key) = (key_pair::random(), key_pair::random());
+            smol::future::zip(
+                async { handshake(&mut &outgoing, &mut &outgoing, ours, &our_key, Direction::Outgoing).await },
+                async {
+                    let session = handshake(&mut &incoming, &mut &incoming, theirs, &their_key, Direction::Incoming).await;
+                    // a node that does not encrypt sends its Version right away
+                    if theirs == Encryption::Off {
+                        framing::write_frame(&mut &incoming, b"version").await.unwrap();
+                    }
+                    session
+                },
+            ).await
+        })
+    }
+
+    fn ciphers(sessions: (Result<Session, String>, Result<Session, String>)) -> (Cipher, Cipher) {
+        match sessions {
+            (Ok(Session::Encrypted { sealer, .. }), Ok(Session::Encrypted { opener, .. })) => (sealer, opener),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn encrypted_session() {
+        let (mut sealer, mut opener) = ciphers(connect(Encryption::On, Encryption::Strict));
+        let frame = sealer.seal(b"first");
+        assert_ne!(&frame[..5], b"first");
+        assert_eq!(opener.open(frame).unwrap(), b"first");
+        // a tampered frame does not open
+        let mut tampered = sealer.seal(b"second");
+        tampered[0] ^= 1;
+        assert!(opener.open(tampered).is_err());
+
+        // neither does a frame out of order
+        let (mut sealer, mut opener) = ciphers(connect(Encryption::On, Encryption::On));
+        let _dropped = sealer.seal(b"first");
+        assert!(opener.open(sealer.seal(b"second")).is_err());
+    }
+
+    #[test]
+    fn signature_covers_transcript() {
+        let rng = rand::SystemRandom::new();
+        let (our_key, their_key) = (key_pair::random(), key_pair::random());
+        let ephemeral = || agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+        let (outgoing, incoming) = (hello(&ephemeral(), &our_key).unwrap(), hello(&ephemeral(), &their_key).unwrap());
+        let signed = our_key.sign(&transcript(Direction::Outgoing, &outgoing, &incoming));
+        let verify = |signer, outgoing: &[u8], incoming: &[u8]| {
+            signature::UnparsedPublicKey::new(&signature::ED25519, our_key.public_key().as_ref())
+                .verify(&transcript(signer, outgoing, incoming), signed.as_ref())
+                .is_ok()
+        };
+        assert!(verify(Direction::Outgoing, &outgoing, &incoming));
+        // not good for the other side of the connection, nor for another handshake
+        assert!(!verify(Direction::Incoming, &incoming, &outgoing));
+        assert!(!verify(Direction::Outgoing, &outgoing, &hello(&ephemeral(), &their_key).unwrap()));
+    }
+
+    #[test]
+    fn plaintext_fallback() {
+        // a node that encrypts when it can falls back, keeping the Version it read
+        match connect(Encryption::On, Encryption::Off) {
+            (Ok(Session::Plain { first_frame: Some(frame) }), Ok(Session::Plain { first_frame: None })) => {
+                assert_eq!(frame, b"version");
+            }
+            _ => panic!(),
+        }
+        // a strict node refuses
+        assert!(connect(Encryption::Strict, Encryption::Off).0.is_err());
+    }
+}
@@ -1,12 +1,18 @@
-use super::message::Message;
+use super::address_book::MAX_ADDR_PER_MESSAGE;
+use super::framing;
+use super::message::{verify_challenge, Message, Version, MAX_BLOCKS, PROTOCOL_VERSION};
+use super::misbehavior::Misbehavior;
+use super::orphan::{OrphanError, OrphanPool};
 use super::peer;
 use super::server::Handle as ServerHandle;
+use super::sync::{SyncState, MAX_HEADERS};
 use crate::types::hash::{H256, Hashable};
-use crate::types::block::Block;
+use crate::types::block::Header;
 use crate::types::mempool::Mempool;
 use crate::blockchain::Blockchain;
+#[cfg(any(test,test_utilities))]
+use crate::blockchain::ConsensusParams;
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
 use crossbeam::channel::Sender;
 
 
@@ -23,11 +29,12 @@ pub struct Worker {
     // The blockchain is now thread-safe using Arc<Mutex<Blockchain>>
     pub blockchain: Arc<Mutex<Blockchain>>,
     pub mempool: Arc<Mutex<Mempool>>,
-    // Change buffer to map from parent_hash -> blocks waiting for that parent
-    orphan_buffer: HashMap<H256, Vec<Block>>, // parent_hash -> blocks
+    // Blocks waiting for their parent, shared by all worker threads
+    orphan_pool: Arc<Mutex<OrphanPool>>,
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
+    sync: Arc<Mutex<SyncState>>,
 }
 
 
@@ -42,13 +49,24 @@ impl Worker {
         Self {
             blockchain,
             mempool,
-            orphan_buffer: HashMap::new(),
+            orphan_pool: Arc::new(Mutex::new(OrphanPool::new())),
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
+            sync: Arc::new(Mutex::new(SyncState::new())),
         }
     }
 
+    /// Progress of the initial block download, for the API
+    pub fn sync_state(&self) -> Arc<Mutex<SyncState>> {
+        Arc::clone(&self.sync)
+    }
+
+    /// The blocks waiting for their parent, for the API
+    pub fn orphan_pool(&self) -> Arc<Mutex<OrphanPool>> {
+        Arc::clone(&self.orphan_pool)
+    }
+
     pub fn start(self) {
         let num_worker = self.num_worker;
         for i in 0..num_worker {
@@ -69,17 +87,83 @@ impl Worker {
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match framing::decode(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Bad message from {}: {}", peer.addr(), e);
+                    if let Some(misbehavior) = e.misbehavior() {
+                        self.server.report(&peer, misbehavior);
+                    }
+                    if e.is_fatal() {
+                        peer.disconnect();
+                    }
+                    continue;
+                }
+            };
             info!("Received message: {:?}", msg);
+            if !peer.version_accepted() && !matches!(msg, Message::Version(_) | Message::VerAck(_)) {
+                debug!("Ignoring message from {} before the version handshake", peer.addr());
+                self.server.report(&peer, Misbehavior::Unsolicited);
+                continue;
+            }
             match msg {
+                Message::Version(version) => {
+                    self.handle_version(version, &mut peer);
+                }
+                Message::VerAck(signature) => {
+                    debug!("Peer {} accepted our version", peer.addr());
+                    let repeated = {
+                        let mut status = peer.status();
+                        status.acknowledged || status.ack_signature.replace(signature).is_some()
+                    };
+                    if repeated {
+                        self.server.report(&peer, Misbehavior::Unsolicited);
+                        continue;
+                    }
+                    self.complete_handshake(&mut peer);
+                }
+                Message::GetHeaders(locator) => {
+                    let headers = self.blockchain.lock().unwrap().headers_after(&locator, MAX_HEADERS);
+                    debug!("Sending {} headers to {}", headers.len(), peer.addr());
+                    peer.write(Message::Headers(headers));
+                }
+                Message::Headers(headers) => {
+                    if !peer.take_headers_request() {
+                        debug!("Ignoring headers from {} that we did not ask for", peer.addr());
+                        self.server.report(&peer, Misbehavior::Unsolicited);
+                        continue;
+                    }
+                    self.handle_headers(headers, &mut peer);
+                }
+                Message::GetAddr => {
+                    let addrs = match peer.listen_addr() {
+                        Some(theirs) => self.server.address_book().sample(MAX_ADDR_PER_MESSAGE, &theirs),
+                        None => self.server.address_book().sample(MAX_ADDR_PER_MESSAGE, &self.server.p2p_addr),
+                    };
+                    debug!("Sending {} addresses to {}", addrs.len(), peer.addr());
+                    peer.write(Message::Addr(addrs));
+                }
+                Message::Addr(addrs) => {
+                    debug!("Received {} addresses from {}", addrs.len(), peer.addr());
+                    let mut book = self.server.address_book();
+                    for addr in addrs {
+                        if addr != self.server.p2p_addr {
+                            book.add(addr);
+                        }
+                    }
+                }
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
                     peer.write(Message::Pong(nonce.to_string()));
                 }
                 Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+                    match peer.pong(&nonce) {
+                        Some(rtt) => debug!("Pong from {} after {:?}", peer.addr(), rtt),
+                        None => debug!("Pong: {}", nonce),
+                    }
                 }
                 Message::NewBlockHashes(hashes) => {
+                    peer.mark_known(&hashes);
                     let mut blocks_to_request = Vec::new();
                     {
                         let blockchain = self.blockchain.lock().unwrap();
@@ -88,9 +172,10 @@ impl Worker {
                                 blocks_to_request.push(hash);
                             }
                         }
+                        blocks_to_request.truncate(MAX_BLOCKS);
                     }
                     if !blocks_to_request.is_empty() {
-                        peer.write(Message::GetBlocks(blocks_to_request));
+                        peer.request_blocks(blocks_to_request);
                     }
                 }
                 // Handle GetBlocks
@@ -103,91 +188,91 @@ impl Worker {
                         }
                     }
                     if !blocks.is_empty() {
+                        let hashes: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+                        peer.mark_known(&hashes);
                         peer.write(Message::Blocks(blocks));
                     }
                 }
                 // Handle Blocks
                 // Handle Blocks
                 Message::Blocks(blocks) => {
+                    let hashes: Vec<H256> = blocks.iter().map(|block| block.hash()).collect();
+                    peer.mark_known(&hashes);
+                    let unrequested = peer.take_unrequested_blocks(&hashes);
+                    {
+                        let mut sync = self.sync.lock().unwrap();
+                        for hash in hashes.iter() {
+                            sync.received(hash);
+                        }
+                    }
                     for block in blocks {
                         let parent_hash = block.get_parent();
                         let block_hash = block.hash();
                 
                         // First check if we already have this block
                         {
+                            let orphan_pool = self.orphan_pool.lock().unwrap();
                             let blockchain = self.blockchain.lock().unwrap();
-                            if blockchain.blocks.contains_key(&block_hash) {
+                            if blockchain.blocks.contains_key(&block_hash) || orphan_pool.contains(&block_hash) {
                                 debug!("Block already known: {:?}", block_hash);
                                 continue;
                             }
                             drop(blockchain);
                         }
+
+                        // Only a genesis block has no parent, and we already know ours
+                        if parent_hash == H256::default() {
+                            warn!("Peer {} is on a different chain (genesis {:?}), disconnecting", peer.addr(), block_hash);
+                            peer.disconnect();
+                            break;
+                        }
                 
-                        // Check parent existence BEFORE doing expensive validations
+                        // Check parent existence BEFORE doing expensive validations. The orphan
+                        // pool stays locked until the block is buffered, so that a thread
+                        // inserting the parent meanwhile still finds it
                         {
+                            let mut orphan_pool = self.orphan_pool.lock().unwrap();
                             let blockchain = self.blockchain.lock().unwrap();
                             if !blockchain.blocks.contains_key(&parent_hash) {
                                 info!("Parent block not found: {:?}, buffering block: {:?}", parent_hash, block_hash);
+                                // ask for the oldest block we miss on the way to our chain, unless
+                                // its header says it is already being downloaded
+                                let missing = orphan_pool.missing_ancestor(&parent_hash);
+                                let downloading = blockchain.has_header(&missing);
                                 drop(blockchain);
-                                
-                                // Add to orphan buffer and request parent
-                                self.orphan_buffer
-                                    .entry(parent_hash)
-                                    .or_insert_with(Vec::new)
-                                    .push(block);
-                                peer.write(Message::GetBlocks(vec![parent_hash]));
-                                continue;
-                            }
-                            drop(blockchain);
-                        }
-                
-                        // Now validate PoW
-                        if block.hash() > block.header.difficulty {
-                            warn!("Block failed PoW check: {:?}", block_hash);
-                            continue;
-                        }
 
-                        // Validate all transactions in the block
-                        let all_transactions_valid = {
-                            let blockchain = self.blockchain.lock().unwrap();
-                            if let Some(parent_state) = blockchain.states.get(&parent_hash) {
-                                block.content.data.iter().all(|tx| {
-                                    let is_valid = tx.verify(parent_state);
-                                    if !is_valid {
-                                        info!("Transaction {:?} in block {:?} failed validation", tx.hash(), block_hash);
+                                match orphan_pool.insert(block, *peer.addr()) {
+                                    Ok(()) => {}
+                                    Err(OrphanError::Duplicate) => continue,
+                                    Err(OrphanError::PeerQuota) => {
+                                        warn!("Peer {} has too many orphans, dropping block {:?}", peer.addr(), block_hash);
+                                        continue;
                                     }
-                                    is_valid
-                                })
-                            } else {
-                                warn!("Parent state not found for block: {:?}", block_hash);
-                                false
+                                }
+                                drop(orphan_pool);
+                                if !downloading {
+                                    peer.request_blocks(vec![missing]);
+                                }
+                                continue;
                             }
-                        };
-
-                        if !all_transactions_valid {
-                            info!("Block {:?} contains invalid transactions, buffering until parent state is valid", block_hash);
-                            self.orphan_buffer
-                                .entry(parent_hash)
-                                .or_insert_with(Vec::new)
-                                .push(block);
-                            continue;
+                            drop(blockchain);
                         }
                 
-                        // Try to insert the block
-                        let insert_success = {
+                        // Try to insert the block; the blockchain enforces every consensus rule
+                        let insert_result = {
                             let mut blockchain = self.blockchain.lock().unwrap();
-                            let success = blockchain.insert(&block).is_ok();
+                            let result = blockchain.insert(&block);
                             drop(blockchain);
-                            success
+                            result
                         };
 
-                        if insert_success {
+                        if let Ok(tip_change) = insert_result {
                             info!("Block inserted: {:?}", block_hash);
                             
                             // Update mempool in separate lock scope
-                            {
+                            if let Some(change) = tip_change {
                                 let mut mempool = self.mempool.lock().unwrap();
-                                mempool.remove_transactions(&block.content.data);
+                                mempool.handle_tip_change(&change);
                                 drop(mempool);
                             }
                             
@@ -196,11 +281,33 @@ impl Worker {
                             
                             // Process orphans after all other operations
                             self.process_orphans(block_hash);
+                        } else if let Err(e) = insert_result {
+                            warn!("Rejected block {:?}: {}", block_hash, e);
+                            if e.is_invalid() {
+                                self.server.report(&peer, Misbehavior::InvalidBlock);
+                            }
                         }
                     }
+
+                    // a block pushed to us is still worth having, but costs the peer like any
+                    // other message we did not ask for
+                    if unrequested > 0 {
+                        debug!("Peer {} sent {} blocks we did not ask for", peer.addr(), unrequested);
+                        self.server.report(&peer, Misbehavior::Unsolicited);
+                    }
+
+                    // keep the initial download going
+                    let (tip_height, best_header_height) = {
+                        let blockchain = self.blockchain.lock().unwrap();
+                        (blockchain.height(&blockchain.tip()).unwrap_or(0), blockchain.best_header_height())
+                    };
+                    let mut sync = self.sync.lock().unwrap();
+                    sync.request_bodies();
+                    sync.log_progress(tip_height, best_header_height);
                 }
 
                 Message::NewTransactionHashes(hashes) => {
+                    peer.mark_known(&hashes);
                     let mut txs_to_request = Vec::new();
 
                     {
@@ -234,6 +341,8 @@ impl Worker {
                     }
                     drop(mempool);
                     if !transactions.is_empty() {
+                        let hashes: Vec<H256> = transactions.iter().map(|tx| tx.hash()).collect();
+                        peer.mark_known(&hashes);
                         peer.write(Message::Transactions(transactions));
                     }
                 }
@@ -241,12 +350,23 @@ impl Worker {
                 Message::Transactions(transactions) => {
                     // Validate transactions with consistent state
                     info!("Received {} transactions", transactions.len());
+                    let hashes: Vec<H256> = transactions.iter().map(|tx| tx.hash()).collect();
+                    peer.mark_known(&hashes);
                     let mut to_broadcast = Vec::new();
                     let mut mempool = self.mempool.lock().unwrap();
                     for tx in transactions {
-                        if mempool.insert(tx.clone()) {
-                            info!("Added new transaction to mempool: {:?}", tx.hash());
-                            to_broadcast.push(tx.hash());
+                        let hash = tx.hash();
+                        match mempool.insert(tx) {
+                            Ok(()) => {
+                                info!("Added new transaction to mempool: {:?}", hash);
+                                to_broadcast.push(hash);
+                            }
+                            // no honest node relays a coinbase or a transaction with a bad signature
+                            Err(e) if e.is_invalid() => {
+                                warn!("Invalid transaction {:?} from {}: {}", hash, peer.addr(), e);
+                                self.server.report(&peer, Misbehavior::InvalidTransaction);
+                            }
+                            Err(e) => debug!("Transaction {:?} from {} not admitted: {}", hash, peer.addr(), e),
                         }
                     }
                     drop(mempool);
@@ -258,61 +378,181 @@ impl Worker {
                     }
                 }
 
-                _ => unimplemented!(),
             }
         }
     }
+
+    /// Accept the Version of a peer and acknowledge it, or drop the peer if it cannot be part of
+    /// our network
+    fn handle_version(&self, version: Version, peer: &mut peer::Handle) {
+        if peer.version_accepted() {
+            debug!("Ignoring repeated version from {}", peer.addr());
+            self.server.report(peer, Misbehavior::Unsolicited);
+            return;
+        }
+        // over an encrypted transport, the peer proved which node it is
+        let transport_id = peer.status().transport_id;
+        if transport_id.is_some_and(|id| id != version.node_id) {
+            warn!("Peer {} claims to be node {:?} but authenticated as {:?}, disconnecting",
+                  peer.addr(), version.node_id, transport_id.unwrap());
+            peer.disconnect();
+            return;
+        }
+        let (genesis, params) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (blockchain.genesis_hash(), blockchain.params().digest())
+        };
+        if version.version != PROTOCOL_VERSION {
+            warn!("Peer {} speaks protocol version {}, we speak {}, disconnecting",
+                  peer.addr(), version.version, PROTOCOL_VERSION);
+            peer.disconnect();
+        } else if version.genesis != genesis {
+            warn!("Peer {} is on a different chain (genesis {:?}), disconnecting", peer.addr(), version.genesis);
+            peer.disconnect();
+        } else if version.params != params {
+            warn!("Peer {} follows other consensus rules (parameters {:?}), disconnecting", peer.addr(), version.params);
+            peer.disconnect();
+        } else if version.nonce == self.server.nonce {
+            info!("Connected to ourselves through {}, disconnecting", peer.addr());
+            if peer.direction() == peer::Direction::Outgoing {
+                self.server.address_book().remove(peer.addr());
+            }
+            peer.disconnect();
+        } else {
+            info!("Peer {} claims to be node {:?} at height {}", peer.addr(), version.node_id, version.best_height);
+            let signature = self.server.sign_challenge(&version.challenge);
+            peer.status().version = Some(version);
+            // remember where the peer can be reached
+            match peer.direction() {
+                peer::Direction::Outgoing => self.server.address_book().mark_success(*peer.addr()),
+                peer::Direction::Incoming => if let Some(addr) = peer.listen_addr() {
+                    self.server.address_book().add(addr);
+                },
+            }
+            peer.write(Message::VerAck(signature));
+            self.complete_handshake(peer);
+        }
+    }
+
+    /// Once we have both the peer's Version and its VerAck, check that it signed our challenge
+    /// with the key of the node it claims to be, so that a replayed Version proves nothing, and
+    /// start syncing with it
+    fn complete_handshake(&self, peer: &mut peer::Handle) {
+        let (node_id, challenge, signature) = match peer.take_handshake_proof() {
+            Some(proof) => proof,
+            None => return,
+        };
+        if !verify_challenge(&node_id, &challenge, &signature) {
+            warn!("Peer {} claims to be node {:?} without its key, disconnecting", peer.addr(), node_id);
+            peer.disconnect();
+            return;
+        }
+        if !self.server.claim_node_id(node_id, *peer.addr()) {
+            info!("Peer {} is node {:?}, which is already connected, disconnecting", peer.addr(), node_id);
+            peer.disconnect();
+            return;
+        }
+        debug!("Peer {} proved to be node {:?}", peer.addr(), node_id);
+        peer.status().acknowledged = true;
+        self.start_sync(peer);
+    }
+
+    /// Once both sides accepted each other, ask the peer for the addresses it knows, download
+    /// from it, and ask for its headers if its chain is longer
+    fn start_sync(&self, peer: &mut peer::Handle) {
+        self.sync.lock().unwrap().add_peer(peer);
+        peer.write(Message::GetAddr);
+        let their_height = peer.status().version.as_ref().map_or(0, |v| v.best_height);
+        let (our_height, locator) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (blockchain.best_header_height() as u64, blockchain.block_locator())
+        };
+        if their_height > our_height {
+            info!("Peer {} is ahead ({} vs {}), requesting headers", peer.addr(), their_height, our_height);
+            peer.request_headers(locator);
+        }
+    }
+
+    /// Check a batch of headers, queue the bodies of the new ones for download, and ask for more
+    /// headers if the peer had more to send
+    fn handle_headers(&self, headers: Vec<Header>, peer: &mut peer::Handle) {
+        let mut new_headers = Vec::new();
+        let (tip_height, best_header_height, locator) = {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            for header in headers.iter() {
+                match blockchain.insert_header(header) {
+                    Ok(true) => new_headers.push(header.hash()),
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("Rejected header {:?} from {}: {}", header.hash(), peer.addr(), e);
+                        if e.is_invalid() {
+                            self.server.report(peer, Misbehavior::InvalidBlock);
+                        }
+                        break;
+                    }
+                }
+            }
+            let tip_height = blockchain.height(&blockchain.tip()).unwrap_or(0);
+            (tip_height, blockchain.best_header_height(), blockchain.block_locator())
+        };
+        debug!("Accepted {} of {} headers from {}", new_headers.len(), headers.len(), peer.addr());
+
+        if headers.len() == MAX_HEADERS && !new_headers.is_empty() {
+            // continue from the last header we accepted
+            let mut next = vec![*new_headers.last().unwrap()];
+            next.extend(locator);
+            peer.request_headers(next);
+        }
+
+        let mut sync = self.sync.lock().unwrap();
+        for hash in new_headers {
+            sync.want(hash);
+        }
+        sync.request_bodies();
+        sync.log_progress(tip_height, best_header_height);
+    }
 }
 
 impl Worker {
+    /// Insert the orphans waiting for `parent_hash`, then the orphans waiting for those, on every
+    /// branch. The descendants of an invalid orphan can never connect, so they are dropped.
     fn process_orphans(&mut self, parent_hash: H256) {
-        let mut blocks_to_process = Vec::new();
-        let mut current_hash = parent_hash;
-        
-        // Collect all orphans that can now be processed
-        while let Some(orphans) = self.orphan_buffer.remove(&current_hash) {
-            for orphan in orphans {
-                let orphan_hash = orphan.hash();
-                blocks_to_process.push(orphan);
-                current_hash = orphan_hash;
-            }
-        }
-    
-        // Process collected orphans
-        for block in blocks_to_process {
-            let block_hash = block.hash();
+        let mut parents = vec![parent_hash];
+        while let Some(parent_hash) = parents.pop() {
+            let orphans = self.orphan_pool.lock().unwrap().take_children(&parent_hash);
+            for block in orphans {
+                let block_hash = block.hash();
             
-            // Validate PoW before taking any locks
-            if block.hash() > block.header.difficulty {
-                warn!("Orphaned block failed PoW check: {:?}", block_hash);
-                continue;
-            }
+                // Try to insert block
+                let insert_result = {
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let result = blockchain.insert(&block);
+                    drop(blockchain);
+                    result
+                };
     
-            // Try to insert block
-            let insert_success = {
-                let mut blockchain = self.blockchain.lock().unwrap();
-                let success = blockchain.insert(&block).is_ok();
-                drop(blockchain);
-                success
-            };
-    
-            if insert_success {
-                info!("Orphaned block inserted: {:?}", block_hash);
+                if let Ok(tip_change) = insert_result {
+                    info!("Orphaned block inserted: {:?}", block_hash);
                 
-                // Update mempool in separate lock scope
-                {
-                    let mut mempool = self.mempool.lock().unwrap();
-                    mempool.remove_transactions(&block.content.data);
-                    drop(mempool);
-                }
+                    // Update mempool in separate lock scope
+                    if let Some(change) = tip_change {
+                        let mut mempool = self.mempool.lock().unwrap();
+                        mempool.handle_tip_change(&change);
+                        drop(mempool);
+                    }
                 
-                // Broadcast after all locks are released
-                self.server.broadcast(Message::NewBlockHashes(vec![block_hash]));
-            } else {
-                // If insertion failed, put block back in orphan buffer
-                self.orphan_buffer.entry(current_hash)
-                    .or_insert_with(Vec::new)
-                    .push(block);
+                    // Broadcast after all locks are released
+                    self.server.broadcast(Message::NewBlockHashes(vec![block_hash]));
+                    parents.push(block_hash);
+                } else if let Err(e) = insert_result {
+                    warn!("Rejected orphaned block {:?}: {}", block_hash, e);
+                    if e.is_invalid() {
+                        let dropped = self.orphan_pool.lock().unwrap().remove_descendants(&block_hash);
+                        if dropped > 0 {
+                            warn!("Dropped {} orphans descending from {:?}", dropped, block_hash);
+                        }
+                    }
+                }
             }
         }
     }
@@ -335,6 +575,27 @@ impl TestMsgSender {
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
     }
+
+    /// Send as `peer`, so that the worker sees the requests it made to it earlier
+    fn send_as(&self, peer: &peer::Handle, msg: Message) {
+        let bytes = bincode::serialize(&msg).unwrap();
+        smol::block_on(self.s.send((bytes, peer.clone()))).unwrap();
+    }
+
+    /// Send bytes that need not decode to a message
+    fn send_raw(&self, bytes: Vec<u8>) -> PeerTestReceiver {
+        let (handle, r) = peer::Handle::test_handle();
+        smol::block_on(self.s.send((bytes, handle))).unwrap();
+        r
+    }
+
+    /// Send as a peer that has not completed the handshake yet
+    fn send_pending(&self, msg: Message) -> PeerTestReceiver {
+        let bytes = bincode::serialize(&msg).unwrap();
+        let (handle, r) = peer::Handle::test_handle_pending();
+        smol::block_on(self.s.send((bytes, handle))).unwrap();
+        r
+    }
 }
 #[cfg(any(test,test_utilities))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
@@ -342,11 +603,12 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     let (server, server_receiver) = ServerHandle::new_for_test();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
 
-    // Initialize blockchain and pass it to worker
-    let blockchain = Blockchain::new();
+    // Initialize blockchain with the easiest difficulty, so that random blocks are valid
+    let blockchain = Blockchain::with_params(ConsensusParams::for_test());
     let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(Mempool::new(Arc::clone(&blockchain))));
 
-    let worker = Worker::new(Arc::clone(&blockchain), 1, msg_chan, &server);
+    let worker = Worker::new(Arc::clone(&blockchain), mempool, 1, msg_chan, &server);
     worker.start(); 
 
 
@@ -365,10 +627,13 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
 #[cfg(test)]
 mod test {
     use ntest::timeout;
-    use crate::types::block::generate_random_block;
-    use crate::types::hash::Hashable;
+    use crate::types::block::{generate_random_block, generate_random_block_at};
+    use crate::types::hash::{H256, Hashable};
 
-    use super::super::message::Message;
+    use super::super::message::{sign_challenge, Message, Version, PROTOCOL_VERSION};
+    use super::super::misbehavior::Misbehavior;
+    use super::super::peer::NodeId;
+    use ring::signature::KeyPair;
     use super::generate_test_worker_and_start;
 
     #[test]
@@ -411,6 +676,160 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_headers() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(Message::GetHeaders(vec![v[0]]));
+        // nothing after the genesis block yet
+        if let Message::Headers(headers) = peer_receiver.recv() {
+            assert!(headers.is_empty());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn version_handshake() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let node_key = crate::types::key_pair::random();
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            genesis: v[0],
+            params: crate::blockchain::ConsensusParams::for_test().digest(),
+            best_height: 0,
+            nonce: 1,
+            listen_addr: "127.0.0.1:6001".parse().unwrap(),
+            node_id: NodeId::from_public_key_bytes(node_key.public_key().as_ref()),
+            challenge: Default::default(),
+        };
+        let mut peer_receiver = test_msg_sender.send_pending(Message::Version(version.clone()));
+        assert!(matches!(peer_receiver.recv(), Message::VerAck(_)));
+
+        let mut foreign = version.clone();
+        foreign.genesis = Default::default();
+        let mut peer_receiver = test_msg_sender.send_pending(Message::Version(foreign));
+        assert!(peer_receiver.recv_or_disconnect().is_none());
+
+        let mut other_rules = version.clone();
+        other_rules.params = Default::default();
+        let mut peer_receiver = test_msg_sender.send_pending(Message::Version(other_rules));
+        assert!(peer_receiver.recv_or_disconnect().is_none());
+
+        let mut outdated = version.clone();
+        outdated.version += 1;
+        let mut peer_receiver = test_msg_sender.send_pending(Message::Version(outdated));
+        assert!(peer_receiver.recv_or_disconnect().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn authenticate_node_id() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let node_key = crate::types::key_pair::random();
+        let version = Version {
+            version: PROTOCOL_VERSION,
+            genesis: v[0],
+            params: crate::blockchain::ConsensusParams::for_test().digest(),
+            best_height: 0,
+            nonce: 1,
+            listen_addr: "127.0.0.1:6001".parse().unwrap(),
+            node_id: NodeId::from_public_key_bytes(node_key.public_key().as_ref()),
+            challenge: Default::default(),
+        };
+        // a connection on which we sent `challenge`, and the node accepted our Version
+        let connect = |port: u16, challenge: [u8; 32]| {
+            let (peer, mut receiver) = super::peer::Handle::test_handle_pending_at(([127, 0, 0, 1], port).into());
+            peer.status().challenge = H256::from(challenge);
+            test_msg_sender.send_as(&peer, Message::Version(version.clone()));
+            assert!(matches!(receiver.recv(), Message::VerAck(_)));
+            (peer, receiver)
+        };
+
+        // the Version of the node replayed before it connects, with its signature of another
+        // challenge, e.g. one it answered on another connection
+        let (impostor, mut impostor_receiver) = connect(12321, [1u8; 32]);
+        let replayed = sign_challenge(&node_key, &H256::from([9u8; 32]));
+        test_msg_sender.send_as(&impostor, Message::VerAck(replayed));
+        assert!(impostor_receiver.recv_or_disconnect().is_none());
+
+        // or with a signature by another key
+        let (forger, mut forger_receiver) = connect(12322, [2u8; 32]);
+        let forged = sign_challenge(&crate::types::key_pair::random(), &H256::from([2u8; 32]));
+        test_msg_sender.send_as(&forger, Message::VerAck(forged));
+        assert!(forger_receiver.recv_or_disconnect().is_none());
+
+        // the node itself connects fine afterwards, and we start syncing with it
+        let (node, mut node_receiver) = connect(12323, [3u8; 32]);
+        let signature = sign_challenge(&node_key, &H256::from([3u8; 32]));
+        test_msg_sender.send_as(&node, Message::VerAck(signature));
+        assert!(matches!(node_receiver.recv(), Message::GetAddr));
+        assert!(node.is_handshaken());
+
+        // while it is connected, another connection proving the same node ID is refused
+        let (twin, mut twin_receiver) = connect(12324, [4u8; 32]);
+        let signature = sign_challenge(&node_key, &H256::from([4u8; 32]));
+        test_msg_sender.send_as(&twin, Message::VerAck(signature));
+        assert!(twin_receiver.recv_or_disconnect().is_none());
+    }
+    #[test]
+    #[timeout(60000)]
+    fn report_malformed_message() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        test_msg_sender.send_raw(vec![0xff; 7]);
+        assert_eq!(server_receiver.recv_report(), Some(Misbehavior::MalformedMessage));
+        // the worker is still alive
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![v[0]]));
+        assert!(matches!(peer_receiver.recv(), Message::Blocks(_)));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn report_unrequested_headers() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        test_msg_sender.send(Message::Headers(vec![]));
+        assert_eq!(server_receiver.recv_report(), Some(Misbehavior::Unsolicited));
+    }
+    #[test]
+    #[timeout(60000)]
+    fn gossip_addresses() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let other: std::net::SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        // the test peer listens on 127.0.0.1:12321
+        let theirs: std::net::SocketAddr = "127.0.0.1:12321".parse().unwrap();
+        test_msg_sender.send(Message::Addr(vec![other, theirs]));
+        // the workers may handle the two messages in any order, so ask until the address is in
+        loop {
+            let mut peer_receiver = test_msg_sender.send(Message::GetAddr);
+            if let Message::Addr(addrs) = peer_receiver.recv() {
+                assert!(!addrs.contains(&theirs));
+                if addrs.contains(&other) {
+                    break;
+                }
+            } else {
+                panic!();
+            }
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn resolve_orphans() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let a = generate_random_block(v.last().unwrap());
+        let b = generate_random_block_at(&a.hash(), 2);
+        let c = generate_random_block_at(&b.hash(), 3);
+        let (peer, mut peer_receiver) = super::peer::Handle::test_handle();
+        test_msg_sender.send_as(&peer, Message::Blocks(vec![c.clone()]));
+        assert!(matches!(peer_receiver.recv(), Message::GetBlocks(hashes) if hashes == vec![b.hash()]));
+        // nobody asked for c, unlike its ancestors
+        assert_eq!(server_receiver.recv_report(), Some(Misbehavior::Unsolicited));
+        // the parent of an orphan is an orphan too, so ask for the block both wait for
+        test_msg_sender.send_as(&peer, Message::Blocks(vec![b.clone()]));
+        assert!(matches!(peer_receiver.recv(), Message::GetBlocks(hashes) if hashes == vec![a.hash()]));
+
+        test_msg_sender.send_as(&peer, Message::Blocks(vec![a.clone()]));
+        for block in [a, b, c].iter() {
+            assert!(matches!(server_receiver.recv().unwrap(), Message::NewBlockHashes(hashes) if hashes == vec![block.hash()]));
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
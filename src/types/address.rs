@@ -21,6 +21,20 @@ impl std::convert::From<[u8; 20]> for Address {
     }
 }
 
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Address, String> {
+        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|e| e.to_string())?;
+        if bytes.len() != 20 {
+            return Err(format!("address must be 20 bytes, got {}", bytes.len()));
+        }
+        let mut buffer = [0u8; 20];
+        buffer.copy_from_slice(&bytes);
+        Ok(Address(buffer))
+    }
+}
+
 impl std::fmt::Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let start = if let Some(precision) = f.precision() {
@@ -61,9 +61,15 @@ pub fn compute_merkle_root(transactions: &[SignedTransaction]) -> H256 {
     merkle_tree.root()
 }
 
-// Function to generate a random block
+// Function to generate a random block, its coinbase that of a child of the genesis block
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
+    generate_random_block_at(parent, 1)
+}
+
+// Function to generate a random block at `height`
+#[cfg(any(test, test_utilities))]
+pub fn generate_random_block_at(parent: &H256, height: u32) -> Block {
     let mut rng = rand::thread_rng();
     let nonce: u32 = rng.gen();
     let difficulty = H256::from([0xff; 32]); // Placeholder difficulty (can be changed)
@@ -71,7 +77,8 @@ pub fn generate_random_block(parent: &H256) -> Block {
     let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
     
     let content = Content {
-        data: vec![], // Empty transactions for this example
+        // Only a coinbase without reward
+        data: vec![SignedTransaction::coinbase(Default::default(), 0, height)],
     };
     
     let merkle_root = compute_merkle_root(&content.data);
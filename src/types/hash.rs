@@ -37,6 +37,20 @@ impl std::fmt::Display for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<H256, String> {
+        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|e| e.to_string())?;
+        if bytes.len() != 32 {
+            return Err(format!("hash must be 32 bytes, got {}", bytes.len()));
+        }
+        let mut buffer = [0u8; 32];
+        buffer.copy_from_slice(&bytes);
+        Ok(H256(buffer))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -1,5 +1,7 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use std::io;
+use std::path::Path;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +9,24 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Load the key pair whose seed is saved at `path`, or generate one and save its seed there.
+pub fn load_or_generate(path: &Path) -> io::Result<Ed25519KeyPair> {
+    if path.exists() {
+        return from_seed_file(path);
+    }
+    let mut seed = [0u8; 32];
+    rand::SecureRandom::fill(&rand::SystemRandom::new(), &mut seed)
+        .map_err(|_| io::Error::other("failed to generate a seed"))?;
+    std::fs::write(path, hex::encode(seed))?;
+    Ok(Ed25519KeyPair::from_seed_unchecked(&seed).unwrap())
+}
+
+/// Load a key pair from a file holding its 32-byte seed, hex encoded.
+pub fn from_seed_file(path: &Path) -> io::Result<Ed25519KeyPair> {
+    let contents = std::fs::read_to_string(path)?;
+    let seed = hex::decode(contents.trim())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ed25519KeyPair::from_seed_unchecked(&seed)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "seed must be 32 bytes"))
+}
@@ -1,52 +1,623 @@
-use std::collections::HashMap;
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::ops::Bound;
+use std::time::{Duration, Instant};
 use super::{
+    address::Address,
     hash::{Hashable, H256},
-    transaction::SignedTransaction,
+    state::{State, TransactionError},
+    transaction::{verify, SignedTransaction},
 };
 use crate::Blockchain;
+use crate::blockchain::TipChange;
+use crate::miner::Handle as MinerHandle;
 use crate::error;
 use std::sync::{Arc, Mutex};
 use crate::info;
 
+/// How far past the next nonce of its sender a transaction may be, waiting for the ones before it
+pub const MAX_NONCE_GAP: u32 = 256;
+/// Fee increase a replacement has to pay, in percent of the fee it replaces, unless configured otherwise
+pub const DEFAULT_REPLACE_BUMP: u64 = 10;
+/// Limits of the pool, unless configured otherwise
+pub const DEFAULT_MAX_TRANSACTIONS: usize = 5000;
+pub const DEFAULT_MAX_BYTES: usize = 2 * 1024 * 1024;
+pub const DEFAULT_MAX_PER_SENDER: usize = 128;
+pub const DEFAULT_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// Relay policy of the mempool, as opposed to the consensus rules
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    pub replace_bump: u64, // Percent by which a replacement must raise the fee of the pending transaction
+    pub max_transactions: usize,
+    pub max_bytes: usize, // Total size of the pending transactions
+    pub max_per_sender: usize,
+    pub ttl: Duration, // A transaction still pending after this long is dropped
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            replace_bump: DEFAULT_REPLACE_BUMP,
+            max_transactions: DEFAULT_MAX_TRANSACTIONS,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_per_sender: DEFAULT_MAX_PER_SENDER,
+            ttl: DEFAULT_TTL,
+        }
+    }
+}
+
+impl MempoolConfig {
+    /// Smallest fee that replaces a pending transaction paying `fee`; always more than `fee`
+    pub fn replacement_fee(&self, fee: u64) -> u64 {
+        let bump = (fee as u128 * self.replace_bump as u128).div_ceil(100);
+        fee.saturating_add((bump as u64).max(1))
+    }
+}
+
+/// Reasons a transaction is refused by `Mempool::insert`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    Coinbase,
+    Duplicate,
+    InvalidSignature,
+    ReplacementUnderpriced { fee: u64, required: u64 }, // The nonce is taken by a transaction paying about as much
+    UnknownSender,
+    StaleNonce { expected: u32, found: u32 }, // Already used at the tip
+    NonceTooHigh { max: u32, found: u32 }, // More than MAX_NONCE_GAP ahead
+    InsufficientBalance { balance: u64, required: u64 }, // Counting the sender's other pending transactions
+    SenderLimit { limit: usize }, // The sender has too many pending transactions
+    PoolFull, // And every transaction that could make room pays at least the same fee rate
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MempoolError::Coinbase => write!(f, "coinbase transactions are never relayed"),
+            MempoolError::Duplicate => write!(f, "transaction already in mempool"),
+            MempoolError::InvalidSignature => write!(f, "invalid signature"),
+            MempoolError::ReplacementUnderpriced { fee, required } => {
+                write!(f, "fee {} too low to replace the pending transaction with the same nonce, requires {}", fee, required)
+            }
+            MempoolError::UnknownSender => write!(f, "sender account not found"),
+            MempoolError::StaleNonce { expected, found } => {
+                write!(f, "nonce {} already used, next is {}", found, expected)
+            }
+            MempoolError::NonceTooHigh { max, found } => write!(f, "nonce {} too far ahead, max {}", found, max),
+            MempoolError::InsufficientBalance { balance, required } => {
+                write!(f, "insufficient balance {}, pending transactions require {}", balance, required)
+            }
+            MempoolError::SenderLimit { limit } => write!(f, "sender already has {} pending transactions", limit),
+            MempoolError::PoolFull => write!(f, "mempool full, fee rate too low to make room"),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+impl MempoolError {
+    /// Short, stable name of the reason, for logs and the API
+    pub fn code(&self) -> &'static str {
+        match self {
+            MempoolError::Coinbase => "coinbase",
+            MempoolError::Duplicate => "duplicate",
+            MempoolError::InvalidSignature => "invalid-signature",
+            MempoolError::ReplacementUnderpriced { .. } => "replacement-underpriced",
+            MempoolError::UnknownSender => "unknown-sender",
+            MempoolError::StaleNonce { .. } => "stale-nonce",
+            MempoolError::NonceTooHigh { .. } => "nonce-too-high",
+            MempoolError::InsufficientBalance { .. } => "insufficient-balance",
+            MempoolError::SenderLimit { .. } => "sender-limit",
+            MempoolError::PoolFull => "pool-full",
+        }
+    }
+
+    /// Whether the transaction could never be valid, as opposed to not fitting our view of the
+    /// chain right now. A peer relaying such a transaction is misbehaving.
+    pub fn is_invalid(&self) -> bool {
+        matches!(self, MempoolError::Coinbase | MempoolError::InvalidSignature)
+    }
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct Mempool {
     pub transactions: HashMap<H256, SignedTransaction>,
+    by_sender: HashMap<Address, SenderQueue>, // Pending transactions of each sender
+    entered: HashMap<H256, Instant>, // When each pending transaction was admitted
+    bytes: usize, // Total size of the pending transactions
     max_block_size: usize,
+    config: MempoolConfig,
+    counters: MempoolCounters,
+    miner: Option<MinerHandle>, // Told when the pending transactions change
     blockchain: Arc<Mutex<Blockchain>>,
 }
 
+/// What left the pool without being confirmed, and what never got in
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct MempoolCounters {
+    pub evicted: u64, // To make room for a better paying transaction
+    pub expired: u64, // Pending for longer than the time-to-live
+    pub replaced: u64, // By a transaction with the same sender and nonce
+    pub invalidated: u64, // By a new tip using their nonce or spending the balance they needed
+    pub rejected: HashMap<&'static str, u64>, // By reason code
+}
+
+/// Usage of the pool, as reported through the API
+#[derive(Serialize, Debug, Clone)]
+pub struct MempoolStats {
+    pub transactions: usize,
+    pub bytes: usize,
+    pub ready: usize,
+    pub future: usize,
+    pub senders: usize,
+    pub max_transactions: usize,
+    pub max_bytes: usize,
+    pub max_per_sender: usize,
+    pub ttl_secs: u64,
+    pub counters: MempoolCounters,
+}
+
+/// Pending transactions of one sender, by nonce
+#[derive(Debug, Default, Clone)]
+struct SenderQueue {
+    ready: BTreeMap<u32, H256>, // Contiguous nonces from the sender's next nonce at the tip
+    future: BTreeMap<u32, H256>, // Behind a gap, waiting for the missing nonces
+}
+
+impl SenderQueue {
+    fn get(&self, nonce: u32) -> Option<&H256> {
+        self.ready.get(&nonce).or_else(|| self.future.get(&nonce))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty() && self.future.is_empty()
+    }
+
+    fn hashes(&self) -> impl Iterator<Item = &H256> {
+        self.ready.values().chain(self.future.values())
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len() + self.future.len()
+    }
+
+    /// The transactions from the highest nonce down, the future ones being after the ready ones
+    fn iter_desc(&self) -> impl Iterator<Item = (&u32, &H256)> {
+        self.future.iter().rev().chain(self.ready.iter().rev())
+    }
+
+    /// Sort the transactions again for `next`, the sender's next nonce at the tip, returning the
+    /// ones whose nonce is already used
+    fn promote(&mut self, next: u32) -> Vec<H256> {
+        let mut all = std::mem::take(&mut self.future);
+        all.append(&mut self.ready);
+        let mut expected = next;
+        let mut stale = Vec::new();
+        for (nonce, hash) in all {
+            if nonce < next {
+                stale.push(hash);
+            } else if nonce == expected {
+                self.ready.insert(nonce, hash);
+                expected += 1;
+            } else {
+                self.future.insert(nonce, hash);
+            }
+        }
+        stale
+    }
+
+    /// Take out the transaction at `nonce`; the ready ones after it now wait behind a gap
+    fn remove(&mut self, nonce: u32, hash: &H256) {
+        if self.ready.get(&nonce) == Some(hash) {
+            self.ready.remove(&nonce);
+            let behind = self.ready.split_off(&nonce);
+            self.future.extend(behind);
+        } else if self.future.get(&nonce) == Some(hash) {
+            self.future.remove(&nonce);
+        }
+    }
+}
+
+/// Fee paid per byte of a transaction, compared without rounding
+#[derive(Debug, Clone, Copy)]
+pub struct FeeRate {
+    fee: u64,
+    size: u64,
+}
+
+impl FeeRate {
+    pub fn of(tx: &SignedTransaction) -> Self {
+        FeeRate {
+            fee: tx.transaction.fee,
+            size: tx.size().max(1) as u64,
+        }
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &FeeRate) -> Ordering {
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &FeeRate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &FeeRate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+/// The next transaction of a sender that may go into a block template
+#[derive(PartialEq, Eq)]
+struct Candidate {
+    rate: FeeRate,
+    hash: H256,
+    sender: Address,
+    nonce: u32,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        // highest fee rate first, then the smaller hash so that the order is deterministic
+        self.rate.cmp(&other.rate).then_with(|| other.hash.cmp(&self.hash))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Mempool {
     pub fn new(blockchain: Arc<Mutex<Blockchain>>) -> Self {
+        Self::with_config(blockchain, MempoolConfig::default())
+    }
+
+    pub fn with_config(blockchain: Arc<Mutex<Blockchain>>, config: MempoolConfig) -> Self {
+        let max_block_size = blockchain.lock().unwrap().params().max_block_size;
         Self {
             transactions: HashMap::new(),
-            max_block_size: 100,
+            by_sender: HashMap::new(),
+            entered: HashMap::new(),
+            bytes: 0,
+            max_block_size,
+            config,
+            counters: MempoolCounters::default(),
+            miner: None,
             blockchain,
         }
     }
 
-    // In insert function:
-    pub fn insert(&mut self, transaction: SignedTransaction) -> bool {
-        let hash = transaction.hash();
-        
-        info!("Attempting to insert transaction {} into mempool", hash);
+    /// Have the miner rebuild its block template whenever the pending transactions change
+    pub fn set_miner(&mut self, miner: MinerHandle) {
+        self.miner = Some(miner);
+    }
+
+    fn notify_miner(&self) {
+        if let Some(miner) = self.miner.as_ref() {
+            miner.update();
+        }
+    }
+
+    /// Admit a transaction that is valid on top of the tip state, or will be once the sender's
+    /// transactions with lower nonces are confirmed. A transaction using the same nonce as a
+    /// pending one of its sender replaces it if it pays enough more.
+    pub fn insert(&mut self, transaction: SignedTransaction) -> Result<(), MempoolError> {
+        info!("Attempting to insert transaction {} into mempool", transaction.hash());
+        self.expire();
+        if let Err(e) = self.admit(transaction) {
+            *self.counters.rejected.entry(e.code()).or_default() += 1;
+            return Err(e);
+        }
+        self.notify_miner();
+        Ok(())
+    }
 
-        if self.transactions.contains_key(&hash) {
-            info!("Transaction already in mempool: {:?}", hash);
-            return false;
+    /// Check a transaction and add it, making room for it if needed. Unlike `insert`, a refusal
+    /// is not counted and the miner is not notified.
+    fn admit(&mut self, transaction: SignedTransaction) -> Result<(), MempoolError> {
+        let hash = transaction.hash();
+        let (next, replaced, evicted) = self.check(&hash, &transaction)
+            .and_then(|(next, replaced)| {
+                let evicted = self.make_room(&transaction, replaced.as_ref())?;
+                Ok((next, replaced, evicted))
+            })
+            .inspect_err(|e| info!("Transaction {:?} refused ({}): {}", hash, e.code(), e))?;
+        if let Some(replaced) = replaced {
+            info!("Transaction {:?} replaces {:?}", hash, replaced);
+            self.remove(&replaced);
+            self.counters.replaced += 1;
+        }
+        for evicted in evicted {
+            info!("Evicting transaction {:?} to make room for {:?}", evicted, hash);
+            self.remove(&evicted);
+            self.counters.evicted += 1;
         }
     
         info!("Adding transaction {:?} to mempool", hash);
+        self.add(hash, transaction, next);
+        Ok(())
+    }
+
+    /// The transactions to evict so that `transaction` fits in the pool, the lowest fee rates
+    /// first. Only the last pending transaction of a sender is evicted, so that the others stay
+    /// valid, and never one of the sender of `transaction`.
+    fn make_room(&self, transaction: &SignedTransaction, replaced: Option<&H256>) -> Result<Vec<H256>, MempoolError> {
+        let freed = replaced.map_or(0, |hash| self.transactions[hash].size());
+        let mut count = self.transactions.len() - replaced.map_or(0, |_| 1) + 1;
+        let mut bytes = self.bytes - freed + transaction.size();
+        let fits = |count: usize, bytes: usize| count <= self.config.max_transactions && bytes <= self.config.max_bytes;
+        if fits(count, bytes) {
+            return Ok(Vec::new());
+        }
+        if transaction.size() > self.config.max_bytes {
+            return Err(MempoolError::PoolFull);
+        }
+
+        let sender = Address::from_public_key_bytes(&transaction.public_key);
+        let rate = FeeRate::of(transaction);
+        // the last transaction of every other sender, the lowest fee rate on top
+        let mut tails: HashMap<Address, Box<dyn Iterator<Item = (&u32, &H256)> + '_>> = self.by_sender.iter()
+            .filter(|(other, _)| **other != sender)
+            .map(|(other, queue)| (*other, Box::new(queue.iter_desc()) as Box<dyn Iterator<Item = _>>))
+            .collect();
+        let mut heap = BinaryHeap::new();
+        for (other, iter) in tails.iter_mut() {
+            if let Some((nonce, hash)) = iter.next() {
+                heap.push(Reverse(self.candidate(other, *nonce, hash)));
+            }
+        }
+        let mut evicted = Vec::new();
+        while !fits(count, bytes) {
+            let Reverse(lowest) = heap.pop().ok_or(MempoolError::PoolFull)?;
+            if lowest.rate >= rate {
+                return Err(MempoolError::PoolFull);
+            }
+            count -= 1;
+            bytes -= self.transactions[&lowest.hash].size();
+            evicted.push(lowest.hash);
+            if let Some((nonce, hash)) = tails.get_mut(&lowest.sender).and_then(|iter| iter.next()) {
+                heap.push(Reverse(self.candidate(&lowest.sender, *nonce, hash)));
+            }
+        }
+        Ok(evicted)
+    }
+
+    fn candidate(&self, sender: &Address, nonce: u32, hash: &H256) -> Candidate {
+        Candidate {
+            rate: FeeRate::of(&self.transactions[hash]),
+            hash: *hash,
+            sender: *sender,
+            nonce,
+        }
+    }
+
+    /// Drop the transactions pending for longer than the time-to-live, with the transactions of
+    /// the same sender that wait for them, returning how many there were
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<H256> = self.entered.iter()
+            .filter(|(_, entered)| now.duration_since(**entered) > self.config.ttl)
+            .map(|(hash, _)| *hash)
+            .collect();
+        let mut dropped = 0;
+        for hash in expired {
+            let transaction = match self.transactions.get(&hash) {
+                Some(transaction) => transaction,
+                None => continue, // went with an earlier one
+            };
+            let sender = Address::from_public_key_bytes(&transaction.public_key);
+            let nonce = transaction.transaction.nonce;
+            let waiting: Vec<H256> = self.by_sender[&sender]
+                .iter_desc()
+                .take_while(|(other, _)| **other >= nonce)
+                .map(|(_, hash)| *hash)
+                .collect();
+            for hash in waiting {
+                info!("Transaction {:?} expired", hash);
+                self.remove(&hash);
+                dropped += 1;
+            }
+        }
+        self.counters.expired += dropped as u64;
+        dropped
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        MempoolStats {
+            transactions: self.transactions.len(),
+            bytes: self.bytes,
+            ready: self.ready_len(),
+            future: self.future_len(),
+            senders: self.by_sender.len(),
+            max_transactions: self.config.max_transactions,
+            max_bytes: self.config.max_bytes,
+            max_per_sender: self.config.max_per_sender,
+            ttl_secs: self.config.ttl.as_secs(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Check a transaction for admission, returning the next nonce of its sender at the tip, and
+    /// the pending transaction it replaces, if any
+    fn check(&self, hash: &H256, transaction: &SignedTransaction) -> Result<(u32, Option<H256>), MempoolError> {
+        if transaction.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+        if self.transactions.contains_key(hash) {
+            return Err(MempoolError::Duplicate);
+        }
+        if !verify(&transaction.transaction, &transaction.public_key, &transaction.signature) {
+            return Err(MempoolError::InvalidSignature);
+        }
+
+        let sender = Address::from_public_key_bytes(&transaction.public_key);
+        let nonce = transaction.transaction.nonce;
+        let pending = self.by_sender.get(&sender);
+        let replaced = pending.and_then(|queue| queue.get(nonce)).copied();
+        if let Some(replaced) = replaced {
+            let fee = transaction.transaction.fee;
+            let required = self.config.replacement_fee(self.transactions[&replaced].transaction.fee);
+            if fee < required {
+                return Err(MempoolError::ReplacementUnderpriced { fee, required });
+            }
+        } else if pending.map_or(0, |queue| queue.len()) >= self.config.max_per_sender {
+            return Err(MempoolError::SenderLimit { limit: self.config.max_per_sender });
+        }
+
+        let account = {
+            let blockchain = self.blockchain.lock().unwrap();
+            blockchain.states.get(&blockchain.tip())
+                .expect("Tip state must exist")
+                .get_account_state(&sender)
+                .cloned()
+                .ok_or(MempoolError::UnknownSender)?
+        };
+        let next = account.nonce + 1;
+        if nonce < next {
+            return Err(MempoolError::StaleNonce { expected: next, found: nonce });
+        }
+        let max = next.saturating_add(MAX_NONCE_GAP);
+        if nonce > max {
+            return Err(MempoolError::NonceTooHigh { max, found: nonce });
+        }
+        // the balance at the tip has to pay for everything the sender has pending
+        let required = pending.into_iter()
+            .flat_map(|queue| queue.hashes())
+            .filter(|hash| Some(**hash) != replaced)
+            .map(|hash| &self.transactions[hash].transaction)
+            .chain(std::iter::once(&transaction.transaction))
+            .fold(0u64, |sum, tx| sum.saturating_add(tx.value).saturating_add(tx.fee));
+        if account.balance < required {
+            return Err(MempoolError::InsufficientBalance { balance: account.balance, required });
+        }
+        Ok((next, replaced))
+    }
+
+    /// Add a checked transaction, `next` being the next nonce of its sender at the tip
+    fn add(&mut self, hash: H256, transaction: SignedTransaction, next: u32) {
+        let sender = Address::from_public_key_bytes(&transaction.public_key);
+        self.by_sender
+            .entry(sender)
+            .or_default()
+            .future
+            .insert(transaction.transaction.nonce, hash);
+        self.bytes += transaction.size();
+        self.entered.insert(hash, Instant::now());
         self.transactions.insert(hash, transaction);
-        true
+        self.promote(&sender, next);
+    }
+
+    fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let transaction = self.transactions.remove(hash)?;
+        self.bytes -= transaction.size();
+        self.entered.remove(hash);
+        let sender = Address::from_public_key_bytes(&transaction.public_key);
+        if let Some(queue) = self.by_sender.get_mut(&sender) {
+            queue.remove(transaction.transaction.nonce, hash);
+            if queue.is_empty() {
+                self.by_sender.remove(&sender);
+            }
+        }
+        Some(transaction)
+    }
+
+    /// Move the transactions of `sender` between the ready and future queues for `next`, its
+    /// next nonce at the tip, and drop the ones whose nonce is already used
+    fn promote(&mut self, sender: &Address, next: u32) {
+        let stale = match self.by_sender.get_mut(sender) {
+            Some(queue) => queue.promote(next),
+            None => return,
+        };
+        for hash in stale {
+            info!("Dropping transaction {:?}, its nonce is already used", hash);
+            if let Some(transaction) = self.transactions.remove(&hash) {
+                self.bytes -= transaction.size();
+                self.entered.remove(&hash);
+            }
+        }
+        if self.by_sender[sender].is_empty() {
+            self.by_sender.remove(sender);
+        }
+    }
+
+    /// Number of transactions that can go into the next block, in nonce order
+    pub fn ready_len(&self) -> usize {
+        self.by_sender.values().map(|queue| queue.ready.len()).sum()
+    }
+
+    /// Number of transactions waiting for a missing nonce of their sender
+    pub fn future_len(&self) -> usize {
+        self.by_sender.values().map(|queue| queue.future.len()).sum()
+    }
+
+    /// Whether the transaction is pending behind a gap in its sender's nonces
+    pub fn is_future(&self, hash: &H256) -> bool {
+        self.transactions.get(hash).is_some_and(|tx| {
+            let sender = Address::from_public_key_bytes(&tx.public_key);
+            self.by_sender[&sender].future.get(&tx.transaction.nonce) == Some(hash)
+        })
     }
 
-    // Get transactions for block creation (up to max_block_size)
+    // Get transactions for block creation (up to max_block_size), highest fee rate first
     pub fn get_transactions(&self) -> Vec<SignedTransaction> {
-        self.transactions.values()
-            .take(self.max_block_size)
-            .cloned()
-            .collect()
+        self.select(|_| Ok(()))
+    }
+
+    /// Pick up to `max_block_size` transactions by fee rate, taking each sender's ready
+    /// transactions in nonce order. `accept` decides whether the next transaction of a sender fits the template;
+    /// a sender whose transaction is rejected contributes nothing further, unless the rejection
+    /// is for a nonce that has already been used, in which case its next nonce is tried instead.
+    fn select<F>(&self, mut accept: F) -> Vec<SignedTransaction>
+    where
+        F: FnMut(&SignedTransaction) -> Result<(), TransactionError>,
+    {
+        let candidate = |sender: &Address, nonce: u32, hash: &H256| self.candidate(sender, nonce, hash);
+        let mut heap: BinaryHeap<Candidate> = self.by_sender
+            .iter()
+            .filter_map(|(sender, queue)| {
+                queue.ready.iter().next().map(|(nonce, hash)| candidate(sender, *nonce, hash))
+            })
+            .collect();
+
+        let mut selected = Vec::new();
+        while selected.len() < self.max_block_size {
+            let head = match heap.pop() {
+                Some(head) => head,
+                None => break,
+            };
+            let tx = &self.transactions[&head.hash];
+            let advance = match accept(tx) {
+                Ok(()) => {
+                    selected.push(tx.clone());
+                    true
+                }
+                Err(TransactionError::InvalidNonce { expected, found }) if found < expected => true,
+                Err(e) => {
+                    info!("Transaction {:?} left out of the block: {}", head.hash, e);
+                    false
+                }
+            };
+            if advance {
+                let next = self.by_sender[&head.sender]
+                    .ready
+                    .range((Bound::Excluded(head.nonce), Bound::Unbounded))
+                    .next();
+                if let Some((nonce, hash)) = next {
+                    heap.push(candidate(&head.sender, *nonce, hash));
+                }
+            }
+        }
+        selected
     }
 
     pub fn contains(&self, hash: &H256) -> bool {
@@ -57,8 +628,85 @@ impl Mempool {
     pub fn remove_transactions(&mut self, transactions: &[SignedTransaction]) {
         for tx in transactions {
             info!("Removing transaction {:?} from mempool", tx.hash());
-            self.transactions.remove(&tx.hash());
+            self.remove(&tx.hash());
+        }
+    }
+
+    /// Bring the mempool in line with a new tip: transactions confirmed by the connected blocks
+    /// are evicted, and transactions that were only confirmed by the disconnected blocks are put
+    /// back if they still apply on top of the new tip state.
+    pub fn handle_tip_change(&mut self, change: &TipChange) {
+        let (disconnected, connected, mut state) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let collect = |hashes: &[H256]| -> Vec<SignedTransaction> {
+                hashes.iter()
+                    .filter_map(|hash| blockchain.get_block(hash))
+                    .flat_map(|block| block.content.data.iter().cloned())
+                    .collect()
+            };
+            // disconnected blocks are listed from the old tip backwards, replay them in chain order
+            let disconnected_blocks: Vec<H256> = change.disconnected.iter().rev().cloned().collect();
+            let state = blockchain.states.get(&change.new_tip)
+                .expect("Tip state must exist")
+                .clone();
+            (collect(&disconnected_blocks), collect(&change.connected), state)
+        };
+
+        let confirmed: HashSet<H256> = connected.iter().map(|tx| tx.hash()).collect();
+        self.remove_transactions(&connected);
+        self.revalidate(&state);
+
+        let mut reinjected = 0;
+        for tx in disconnected {
+            let hash = tx.hash();
+            if confirmed.contains(&hash) {
+                continue;
+            }
+            // apply on a scratch state so that a sender's consecutive transactions stay valid;
+            // fees only matter for the template, so they are credited to nobody in particular
+            if state.process_transaction(&tx, &Address::default()).is_ok() && self.admit(tx).is_ok() {
+                reinjected += 1;
+            }
+        }
+        if change.is_reorg() {
+            info!("Reorg: re-added {} transactions from {} disconnected blocks to mempool",
+                  reinjected, change.disconnected.len());
         }
+        // the template of the miner is on the old tip
+        self.notify_miner();
+    }
+
+    /// Check every pending transaction again against `state`, the state at a new tip. The ones
+    /// whose nonce got used, or that their sender can no longer pay for together with its
+    /// earlier ones, are dropped; the ones whose missing nonces got confirmed become ready.
+    fn revalidate(&mut self, state: &State) {
+        self.expire();
+        let mut invalid = Vec::new();
+        for (sender, queue) in self.by_sender.iter_mut() {
+            let account = match state.get_account_state(sender) {
+                Some(account) => account,
+                None => {
+                    invalid.extend(queue.hashes().cloned());
+                    continue;
+                }
+            };
+            invalid.extend(queue.promote(account.nonce + 1));
+            let mut required = 0u64;
+            for hash in queue.hashes() {
+                let tx = &self.transactions[hash].transaction;
+                required = required.saturating_add(tx.value).saturating_add(tx.fee);
+                if required > account.balance {
+                    invalid.push(*hash);
+                }
+            }
+        }
+        for hash in invalid.iter() {
+            info!("Dropping transaction {:?}, no longer valid at the new tip", hash);
+            self.remove(hash);
+        }
+        // queues emptied by promote are not cleaned up by remove
+        self.by_sender.retain(|_, queue| !queue.is_empty());
+        self.counters.invalidated += invalid.len() as u64;
     }
 
     // Get a specific transaction by its hash
@@ -66,27 +714,318 @@ impl Mempool {
         self.transactions.get(hash)
     }
 
-    pub fn validate_transactions(&self) -> Vec<SignedTransaction> {
+    /// Pick the transactions of the next block template, the best fee rates first, such that they
+    /// apply in order on top of the tip state with their fees credited to `miner`
+    pub fn validate_transactions(&self, miner: &Address) -> Vec<SignedTransaction> {
         let current_state = {
             let blockchain = self.blockchain.lock().unwrap();
             blockchain.states.get(&blockchain.tip())
                 .expect("Tip state must exist")
                 .clone()
         };
-    
-        // Filter valid transactions without modifying mempool
-        self.transactions.values()
-            .filter(|tx| {
-                let is_valid = tx.verify(&current_state);
-                if is_valid {
-                    info!("Transaction {:?} passed validation", tx.hash());
-                } else {
-                    info!("Transaction {:?} failed validation", tx.hash());
-                }
-                is_valid
-            })
-            .cloned()
-            .take(self.max_block_size)
-            .collect()
+        self.validate_transactions_on(current_state, miner)
     }
-}
\ No newline at end of file
+
+    /// Like `validate_transactions`, on top of `state` rather than the tip state, e.g. the state
+    /// after a block the blockchain does not have yet
+    pub fn validate_transactions_on(&self, state: State, miner: &Address) -> Vec<SignedTransaction> {
+        // Select valid transactions without modifying mempool, applying them in turn to a
+        // scratch state so that the selected set is valid as a whole
+        let mut scratch_state = state;
+        self.select(|tx| scratch_state.process_transaction(tx, miner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{retrieve_keypair, ConsensusParams};
+    use crate::types::address::Address;
+    use crate::types::block::{compute_merkle_root, generate_random_block_at, Block};
+    use crate::types::key_pair;
+    use crate::types::transaction::Transaction;
+    use ring::signature::KeyPair;
+
+    fn test_mempool() -> (Arc<Mutex<Blockchain>>, Mempool) {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(ConsensusParams::for_test())));
+        let mempool = Mempool::new(Arc::clone(&blockchain));
+        (blockchain, mempool)
+    }
+
+    fn block_with(parent: &H256, height: u32, data: Vec<SignedTransaction>) -> Block {
+        let mut block = generate_random_block_at(parent, height);
+        block.content.data.extend(data);
+        block.header.merkle_root = compute_merkle_root(&block.content.data);
+        block
+    }
+
+    #[test]
+    fn reinject_on_reorg() {
+        let (blockchain, mut mempool) = test_mempool();
+        let key = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let tx = SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value: 10, nonce: 1, fee: 0 },
+            &key,
+        );
+        mempool.insert(tx.clone()).unwrap();
+
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let block = block_with(&genesis_hash, 1, vec![tx.clone()]);
+        let change = blockchain.lock().unwrap().insert(&block).unwrap().unwrap();
+        mempool.handle_tip_change(&change);
+        assert!(!mempool.contains(&tx.hash()));
+
+        // a heavier branch without the transaction takes over
+        let fork_1 = block_with(&genesis_hash, 1, vec![]);
+        let fork_2 = block_with(&fork_1.hash(), 2, vec![]);
+        let mut changes = vec![];
+        for fork in [fork_1, fork_2].iter() {
+            if let Some(change) = blockchain.lock().unwrap().insert(fork).unwrap() {
+                changes.push(change);
+            }
+        }
+        for change in changes.iter() {
+            mempool.handle_tip_change(change);
+        }
+        assert!(changes.iter().any(|c| c.is_reorg()));
+        assert!(mempool.contains(&tx.hash()));
+
+        // the transaction gets confirmed again on the new branch
+        let tip = blockchain.lock().unwrap().tip();
+        let block = block_with(&tip, 3, vec![tx.clone()]);
+        let change = blockchain.lock().unwrap().insert(&block).unwrap().unwrap();
+        mempool.handle_tip_change(&change);
+        assert!(!mempool.contains(&tx.hash()));
+    }
+
+    #[test]
+    fn reinjection_is_not_a_rejection() {
+        let (blockchain, mut mempool) = test_mempool();
+        let key = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let tx = SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value: 10, nonce: 1, fee: 0 },
+            &key,
+        );
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let block = block_with(&genesis_hash, 1, vec![tx.clone()]);
+        let change = blockchain.lock().unwrap().insert(&block).unwrap().unwrap();
+        mempool.handle_tip_change(&change);
+
+        // the pool has no room left for the disconnected transaction
+        mempool.config.max_transactions = 0;
+        let fork_1 = block_with(&genesis_hash, 1, vec![]);
+        let fork_2 = block_with(&fork_1.hash(), 2, vec![]);
+        for fork in [fork_1, fork_2].iter() {
+            let change = blockchain.lock().unwrap().insert(fork).unwrap();
+            if let Some(change) = change {
+                mempool.handle_tip_change(&change);
+            }
+        }
+        assert!(!mempool.contains(&tx.hash()));
+        assert!(mempool.stats().counters.rejected.is_empty());
+    }
+
+    #[test]
+    fn template_by_fee_rate() {
+        let (_blockchain, mut mempool) = test_mempool();
+        let alice = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let bob = retrieve_keypair("127.0.0.1:6001".parse().unwrap());
+        let transfer = |key, nonce, fee| SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value: 10, nonce, fee },
+            key,
+        );
+        let alice_1 = transfer(&alice, 1, 1);
+        let alice_2 = transfer(&alice, 2, 100);
+        let bob_1 = transfer(&bob, 1, 50);
+        let bob_3 = transfer(&bob, 3, 1000); // nonce gap, never valid yet
+        for tx in [&alice_2, &bob_3, &alice_1, &bob_1].iter() {
+            mempool.insert((*tx).clone()).unwrap();
+        }
+        // a second transaction for a pending nonce has to pay more to replace it
+        let conflict = SignedTransaction::new(
+            Transaction { receiver: Address::from([8u8; 20]), value: 10, nonce: 1, fee: 1 },
+            &alice,
+        );
+        assert_eq!(mempool.insert(conflict), Err(MempoolError::ReplacementUnderpriced { fee: 1, required: 2 }));
+
+        let miner = Address::from([9u8; 20]);
+        let hashes: Vec<H256> = mempool.validate_transactions(&miner).iter().map(|tx| tx.hash()).collect();
+        // alice_2 pays the most but has to wait for alice_1
+        assert_eq!(hashes, vec![bob_1.hash(), alice_1.hash(), alice_2.hash()]);
+
+        mempool.max_block_size = 1;
+        assert_eq!(mempool.validate_transactions(&miner)[0].hash(), bob_1.hash());
+    }
+
+    #[test]
+    fn future_nonces_wait() {
+        let (blockchain, mut mempool) = test_mempool();
+        let alice = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let transfer = |nonce| SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value: 10, nonce, fee: 1 },
+            &alice,
+        );
+        let (tx_1, tx_2, tx_3, tx_4) = (transfer(1), transfer(2), transfer(3), transfer(4));
+        let miner = Address::from([9u8; 20]);
+        let template = |mempool: &Mempool| -> Vec<H256> {
+            mempool.validate_transactions(&miner).iter().map(|tx| tx.hash()).collect()
+        };
+
+        mempool.insert(tx_3.clone()).unwrap();
+        mempool.insert(tx_1.clone()).unwrap();
+        assert!(mempool.is_future(&tx_3.hash()));
+        assert_eq!(template(&mempool), vec![tx_1.hash()]);
+
+        // filling the gap makes the rest ready, and they all fit in one block
+        mempool.insert(tx_2.clone()).unwrap();
+        assert_eq!((mempool.ready_len(), mempool.future_len()), (3, 0));
+        assert_eq!(template(&mempool), vec![tx_1.hash(), tx_2.hash(), tx_3.hash()]);
+
+        // once the first two are confirmed, the third is the next one
+        mempool.insert(tx_4.clone()).unwrap();
+        let genesis_hash = blockchain.lock().unwrap().tip();
+        let block = block_with(&genesis_hash, 1, vec![tx_1, tx_2]);
+        let change = blockchain.lock().unwrap().insert(&block).unwrap().unwrap();
+        mempool.handle_tip_change(&change);
+        assert_eq!(template(&mempool), vec![tx_3.hash(), tx_4.hash()]);
+        assert_eq!((mempool.ready_len(), mempool.future_len()), (2, 0));
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let (_blockchain, mut mempool) = test_mempool();
+        let alice = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let transfer = |nonce, value, fee| SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value, nonce, fee },
+            &alice,
+        );
+        let original = transfer(1, 10, 100);
+        let next = transfer(2, 10, 100);
+        mempool.insert(original.clone()).unwrap();
+        mempool.insert(next.clone()).unwrap();
+
+        // a 10% higher fee is needed by default
+        assert_eq!(
+            mempool.insert(transfer(1, 20, 109)),
+            Err(MempoolError::ReplacementUnderpriced { fee: 109, required: 110 })
+        );
+        let replacement = transfer(1, 20, 110);
+        mempool.insert(replacement.clone()).unwrap();
+        assert!(!mempool.contains(&original.hash()));
+        assert_eq!(mempool.transactions.len(), 2);
+        let miner = Address::from([9u8; 20]);
+        let hashes: Vec<H256> = mempool.validate_transactions(&miner).iter().map(|tx| tx.hash()).collect();
+        assert_eq!(hashes, vec![replacement.hash(), next.hash()]);
+
+        // the margin is configurable
+        mempool.config.replace_bump = 0;
+        assert_eq!(mempool.config.replacement_fee(110), 111);
+        mempool.insert(transfer(1, 20, 111)).unwrap();
+        assert!(!mempool.contains(&replacement.hash()));
+    }
+
+    #[test]
+    fn limits_and_eviction() {
+        let (blockchain, _) = test_mempool();
+        let config = MempoolConfig {
+            max_transactions: 3,
+            max_per_sender: 2,
+            ..Default::default()
+        };
+        let mut mempool = Mempool::with_config(blockchain, config);
+        let keys: Vec<_> = (6000..6003).map(|port| retrieve_keypair(([127, 0, 0, 1], port).into())).collect();
+        let transfer = |key, nonce, fee| SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value: 10, nonce, fee },
+            key,
+        );
+        let (alice, bob, carol) = (&keys[0], &keys[1], &keys[2]);
+        mempool.insert(transfer(alice, 1, 2)).unwrap();
+        let alice_2 = transfer(alice, 2, 1);
+        mempool.insert(alice_2.clone()).unwrap();
+        assert_eq!(mempool.insert(transfer(alice, 3, 100)), Err(MempoolError::SenderLimit { limit: 2 }));
+        mempool.insert(transfer(bob, 1, 5)).unwrap();
+
+        // full: only a better fee rate gets in, pushing out the cheapest last transaction
+        assert_eq!(mempool.insert(transfer(carol, 1, 1)), Err(MempoolError::PoolFull));
+        mempool.insert(transfer(carol, 1, 10)).unwrap();
+        assert_eq!(mempool.transactions.len(), 3);
+        assert!(!mempool.contains(&alice_2.hash()));
+
+        let stats = mempool.stats();
+        assert_eq!((stats.counters.evicted, stats.counters.rejected["pool-full"]), (1, 1));
+        assert_eq!(stats.bytes, mempool.transactions.values().map(|tx| tx.size()).sum::<usize>());
+
+        mempool.config.ttl = Duration::from_millis(0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(mempool.expire(), 3);
+        assert_eq!((mempool.stats().bytes, mempool.stats().counters.expired), (0, 3));
+    }
+
+    #[test]
+    fn revalidate_on_new_tip() {
+        let (blockchain, mut mempool) = test_mempool();
+        let alice = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let transfer = |nonce, value, receiver| SignedTransaction::new(
+            Transaction { receiver: Address::from([receiver; 20]), value, nonce, fee: 1 },
+            &alice,
+        );
+        let balance = blockchain.lock().unwrap().states.values().next().unwrap()
+            .get_account_state(&Address::from_public_key_bytes(alice.public_key().as_ref())).unwrap().balance;
+        let (pending_1, pending_2, pending_4) = (transfer(1, 10, 7), transfer(2, 10, 7), transfer(4, 10, 7));
+        for tx in [&pending_1, &pending_2, &pending_4].iter() {
+            mempool.insert((*tx).clone()).unwrap();
+        }
+        let extend = |data| {
+            let tip = blockchain.lock().unwrap().tip();
+            let height = blockchain.lock().unwrap().height(&tip).unwrap() as u32 + 1;
+            let block = block_with(&tip, height, data);
+            blockchain.lock().unwrap().insert(&block).unwrap().unwrap()
+        };
+
+        // another transaction with nonce 1 gets confirmed
+        let change = extend(vec![transfer(1, 10, 8)]);
+        mempool.handle_tip_change(&change);
+        assert!(!mempool.contains(&pending_1.hash()));
+        assert_eq!((mempool.ready_len(), mempool.future_len()), (1, 1));
+
+        // nonce 3 fills the gap, but leaves too little for the transaction with nonce 4
+        let change = extend(vec![transfer(2, 10, 8), transfer(3, balance - 30, 8)]);
+        mempool.handle_tip_change(&change);
+        assert_eq!(mempool.transactions.len(), 0);
+        assert_eq!(mempool.stats().counters.invalidated, 3);
+    }
+
+    #[test]
+    fn admission_checks() {
+        let (_blockchain, mut mempool) = test_mempool();
+        let alice = retrieve_keypair("127.0.0.1:6000".parse().unwrap());
+        let transfer = |key, nonce, value| SignedTransaction::new(
+            Transaction { receiver: Address::from([7u8; 20]), value, nonce, fee: 1 },
+            key,
+        );
+        let tx = transfer(&alice, 1, 10);
+        mempool.insert(tx.clone()).unwrap();
+        assert_eq!(mempool.insert(tx.clone()), Err(MempoolError::Duplicate));
+
+        let mut forged = transfer(&alice, 2, 10);
+        forged.transaction.value = 1000;
+        let error = mempool.insert(forged).unwrap_err();
+        assert!(error.is_invalid());
+        assert_eq!(error.code(), "invalid-signature");
+
+        let stranger = key_pair::random();
+        assert_eq!(mempool.insert(transfer(&stranger, 1, 10)), Err(MempoolError::UnknownSender));
+        assert!(matches!(mempool.insert(transfer(&alice, 0, 10)), Err(MempoolError::StaleNonce { expected: 1, .. })));
+        // a later nonce waits for the ones before it, within limits
+        mempool.insert(transfer(&alice, 3, 10)).unwrap();
+        let too_far = transfer(&alice, 2 + MAX_NONCE_GAP, 10);
+        assert!(matches!(mempool.insert(too_far), Err(MempoolError::NonceTooHigh { .. })));
+        // the pending transactions are paid for first
+        let balance = mempool.blockchain.lock().unwrap().states.values().next().unwrap()
+            .get_account_state(&Address::from_public_key_bytes(alice.public_key().as_ref())).unwrap().balance;
+        let error = mempool.insert(transfer(&alice, 2, balance - 22)).unwrap_err();
+        assert!(matches!(error, MempoolError::InsufficientBalance { .. }));
+        assert!(!error.is_invalid());
+        mempool.insert(transfer(&alice, 2, balance - 23)).unwrap();
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use crate::types::address::Address;
 use serde::{Serialize, Deserialize};
-use crate::types::transaction::SignedTransaction;
+use crate::types::transaction::{verify, SignedTransaction};
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct AccountState {
@@ -45,30 +45,136 @@ impl State {
         }
     }
 
-    pub fn process_transaction(&mut self, tx: &SignedTransaction) -> Result<(), String> {
+    /// Credit the coinbase of the block at `height` to its receiver, allowing at most `max_reward`.
+    /// The nonce of a coinbase must be the height of its block.
+    pub fn process_coinbase(&mut self, tx: &SignedTransaction, height: u64, max_reward: u64) -> Result<(), TransactionError> {
+        if !tx.is_coinbase() {
+            return Err(TransactionError::MissingCoinbase);
+        }
+        if u64::from(tx.transaction.nonce) != height {
+            return Err(TransactionError::WrongCoinbaseHeight {
+                expected: height,
+                found: tx.transaction.nonce,
+            });
+        }
+        if tx.transaction.value > max_reward {
+            return Err(TransactionError::ExcessiveReward {
+                allowed: max_reward,
+                found: tx.transaction.value,
+            });
+        }
+        let balance = self.balance_of(&tx.transaction.receiver);
+        self.set_balance(&tx.transaction.receiver, credit(balance, tx.transaction.value)?);
+        Ok(())
+    }
+
+    fn balance_of(&self, address: &Address) -> u64 {
+        self.get_account_state(address).map_or(0, |account| account.balance)
+    }
+
+    fn set_balance(&mut self, address: &Address, balance: u64) {
+        if self.accounts.contains_key(address) {
+            self.update_balance(address, balance);
+        } else {
+            self.create_account(*address, balance);
+        }
+    }
+
+    /// Apply a transfer, charging its fee to the sender and crediting it to `miner`
+    pub fn process_transaction(&mut self, tx: &SignedTransaction, miner: &Address) -> Result<(), TransactionError> {
+        if tx.is_coinbase() {
+            return Err(TransactionError::UnexpectedCoinbase);
+        }
         let sender = Address::from_public_key_bytes(&tx.public_key);
         
         // Get sender's account
         let sender_account = self.get_account_state(&sender)
-            .ok_or("Sender account not found")?;
+            .ok_or(TransactionError::UnknownSender)?
+            .clone();
         
         // Verify signature (this proves ownership)
-        if !tx.verify(&self) {
-            return Err("Invalid signature".to_string());
+        if !verify(&tx.transaction, &tx.public_key, &tx.signature) {
+            return Err(TransactionError::InvalidSignature);
+        }
+        if tx.transaction.nonce != sender_account.nonce + 1 {
+            return Err(TransactionError::InvalidNonce {
+                expected: sender_account.nonce + 1,
+                found: tx.transaction.nonce,
+            });
+        }
+        let required = tx.transaction.value.saturating_add(tx.transaction.fee);
+        if sender_account.balance < required {
+            return Err(TransactionError::InsufficientBalance {
+                balance: sender_account.balance,
+                required,
+            });
+        }
+
+        // Work out the balances of the sender, receiver and miner before changing any, so that a
+        // refused transaction leaves the state as it was. They may be the same account.
+        let mut balances = vec![(sender, sender_account.balance - required)];
+        let mut credits = vec![(tx.transaction.receiver, tx.transaction.value)];
+        if tx.transaction.fee > 0 {
+            credits.push((*miner, tx.transaction.fee));
+        }
+        for (address, value) in credits {
+            let balance = match balances.iter().rev().find(|(updated, _)| *updated == address) {
+                Some((_, balance)) => *balance,
+                None => self.balance_of(&address),
+            };
+            balances.push((address, credit(balance, value)?));
         }
 
-        // Update sender
-        self.update_balance(&sender, sender_account.balance - tx.transaction.value);
         self.increment_nonce(&sender);
-        
-        // Update receiver
-        let receiver = &tx.transaction.receiver;
-        if let Some(receiver_account) = self.get_account_state(receiver) {
-            self.update_balance(receiver, receiver_account.balance + tx.transaction.value);
-        } else {
-            self.create_account(*receiver, tx.transaction.value);
+        for (address, balance) in balances {
+            self.set_balance(&address, balance);
         }
-        
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+fn credit(balance: u64, value: u64) -> Result<u64, TransactionError> {
+    balance.checked_add(value).ok_or(TransactionError::BalanceOverflow { balance, credit: value })
+}
+
+/// Reasons a transaction cannot be applied to a state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    UnknownSender,
+    InvalidSignature,
+    InvalidNonce { expected: u32, found: u32 },
+    InsufficientBalance { balance: u64, required: u64 },
+    MissingCoinbase,
+    UnexpectedCoinbase,
+    WrongCoinbaseHeight { expected: u64, found: u32 },
+    ExcessiveReward { allowed: u64, found: u64 },
+    BalanceOverflow { balance: u64, credit: u64 },
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransactionError::UnknownSender => write!(f, "sender account not found"),
+            TransactionError::InvalidSignature => write!(f, "invalid signature"),
+            TransactionError::InvalidNonce { expected, found } => {
+                write!(f, "invalid nonce {}, expected {}", found, expected)
+            }
+            TransactionError::InsufficientBalance { balance, required } => {
+                write!(f, "insufficient balance {}, requires {}", balance, required)
+            }
+            TransactionError::MissingCoinbase => write!(f, "block does not start with a coinbase"),
+            TransactionError::UnexpectedCoinbase => write!(f, "coinbase outside the first position"),
+            TransactionError::WrongCoinbaseHeight { expected, found } => {
+                write!(f, "coinbase carries height {}, expected {}", found, expected)
+            }
+            TransactionError::ExcessiveReward { allowed, found } => {
+                write!(f, "coinbase pays {}, allowed {}", found, allowed)
+            }
+            TransactionError::BalanceOverflow { balance, credit } => {
+                write!(f, "crediting {} overflows balance {}", credit, balance)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
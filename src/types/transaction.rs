@@ -15,6 +15,7 @@ pub struct Transaction {
     pub receiver: Address,
     pub value: u64,
     pub nonce: u32,
+    pub fee: u64, // Paid by the sender on top of `value`, goes to the miner of the block
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -43,6 +44,31 @@ impl SignedTransaction {
         }
     }
 
+    /// Create the coinbase transaction of the block at `height`, paying `value` to `receiver`.
+    /// A coinbase has no signature and no public key; its nonce carries the block height so that
+    /// coinbases of different blocks have different hashes.
+    pub fn coinbase(receiver: Address, value: u64, height: u32) -> Self {
+        SignedTransaction {
+            transaction: Transaction {
+                receiver,
+                value,
+                nonce: height,
+                fee: 0,
+            },
+            signature: vec![],
+            public_key: vec![],
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.public_key.is_empty() && self.signature.is_empty()
+    }
+
+    /// Size of the transaction on the wire, in bytes
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).expect("Failed to size SignedTransaction") as usize
+    }
+
     pub fn verify(&self, state: &State) -> bool {
         // 1. Signature verification
         let verification_result = UnparsedPublicKey::new(
@@ -67,7 +93,7 @@ impl SignedTransaction {
                 return false;
             }
             // Check balance
-            if account.balance < self.transaction.value {
+            if account.balance < self.transaction.value.saturating_add(self.transaction.fee) {
                 return false;
             }
         } else {
@@ -117,11 +143,13 @@ pub fn generate_random_transaction() -> Transaction {
     // Generate a random value and nonce
     let value = rng.gen_range(1..1000);
     let nonce = rng.gen_range(0..1000);
+    let fee = rng.gen_range(0..10);
 
     Transaction {
         receiver,
         value,
         nonce,
+        fee,
     }
 }
 
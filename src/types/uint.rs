@@ -0,0 +1,197 @@
+use serde::{Serialize, Deserialize};
+use crate::types::hash::H256;
+use std::convert::TryInto;
+
+/// A 256-bit unsigned integer, used for arithmetic on difficulty targets.
+#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct U256([u64; 4]); // little endian limbs
+
+impl U256 {
+    pub fn max_value() -> U256 {
+        U256([u64::MAX; 4])
+    }
+
+    pub fn from_u64(value: u64) -> U256 {
+        U256([value, 0, 0, 0])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&limb| limb == 0)
+    }
+
+    pub fn checked_add(&self, rhs: &U256) -> Option<U256> {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for (i, out) in result.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(rhs.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *out = sum;
+            carry = c1 || c2;
+        }
+        if carry {
+            None
+        } else {
+            Some(U256(result))
+        }
+    }
+
+    pub fn saturating_add(&self, rhs: &U256) -> U256 {
+        self.checked_add(rhs).unwrap_or_else(U256::max_value)
+    }
+
+    /// Multiply by a u64, returning `None` on overflow.
+    pub fn checked_mul_u64(&self, rhs: u64) -> Option<U256> {
+        let mut result = [0u64; 4];
+        let mut carry: u128 = 0;
+        for (limb, out) in self.0.iter().zip(result.iter_mut()) {
+            let product = *limb as u128 * rhs as u128 + carry;
+            *out = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            None
+        } else {
+            Some(U256(result))
+        }
+    }
+
+    /// Divide by a non-zero u64.
+    pub fn div_u64(&self, rhs: u64) -> U256 {
+        assert!(rhs != 0, "division by zero");
+        let mut result = [0u64; 4];
+        let mut remainder: u128 = 0;
+        for i in (0..4).rev() {
+            let dividend = (remainder << 64) | self.0[i] as u128;
+            result[i] = (dividend / rhs as u128) as u64;
+            remainder = dividend % rhs as u128;
+        }
+        U256(result)
+    }
+
+    /// Divide by a non-zero U256, using binary long division.
+    pub fn div(&self, rhs: &U256) -> U256 {
+        assert!(!rhs.is_zero(), "division by zero");
+        let mut quotient = [0u64; 4];
+        let mut remainder = U256::default();
+        for bit in (0..256).rev() {
+            // remainder = remainder * 2 + next bit of the dividend
+            let overflow = remainder.0[3] >> 63 == 1;
+            remainder = remainder.shl1();
+            remainder.0[0] |= (self.0[bit / 64] >> (bit % 64)) & 1;
+            if overflow || remainder >= *rhs {
+                remainder = remainder.wrapping_sub(rhs);
+                quotient[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        U256(quotient)
+    }
+
+    fn shl1(&self) -> U256 {
+        let mut result = [0u64; 4];
+        for (i, out) in result.iter_mut().enumerate() {
+            *out = self.0[i] << 1;
+            if i > 0 {
+                *out |= self.0[i - 1] >> 63;
+            }
+        }
+        U256(result)
+    }
+
+    fn wrapping_sub(&self, rhs: &U256) -> U256 {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for (i, out) in result.iter_mut().enumerate() {
+            let (diff, b1) = self.0[i].overflowing_sub(rhs.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *out = diff;
+            borrow = b1 || b2;
+        }
+        U256(result)
+    }
+}
+
+impl std::ops::Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+impl std::fmt::Display for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", H256::from(*self))
+    }
+}
+
+impl std::convert::From<H256> for U256 {
+    fn from(input: H256) -> U256 {
+        let bytes: [u8; 32] = input.into();
+        let mut limbs = [0u64; 4];
+        for i in 0..4 {
+            limbs[3 - i] = u64::from_be_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+}
+
+impl std::convert::From<U256> for H256 {
+    fn from(input: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        for i in 0..4 {
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&input.0[3 - i].to_be_bytes());
+        }
+        bytes.into()
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> std::cmp::Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn h256_round_trip() {
+        let h: H256 = hex!("00007fffffffffffffffffffffffffffffffffffffffffffffffffffffffff01").into();
+        let u = U256::from(h);
+        assert_eq!(H256::from(u), h);
+        assert!(u < U256::from(H256::from(hex!("0000800000000000000000000000000000000000000000000000000000000000"))));
+    }
+
+    #[test]
+    fn mul_div() {
+        let h: H256 = hex!("00007fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        let u = U256::from(h);
+        let doubled = u.checked_mul_u64(2).unwrap();
+        assert_eq!(
+            H256::from(doubled),
+            hex!("0000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe").into()
+        );
+        assert_eq!(doubled.div_u64(2), u);
+        assert!(U256::max_value().checked_mul_u64(2).is_none());
+    }
+
+    #[test]
+    fn long_division() {
+        let dividend = U256::max_value();
+        let divisor: U256 = H256::from(hex!("0000000000000000000000000000000100000000000000000000000000000000")).into();
+        assert_eq!(
+            H256::from(dividend.div(&divisor)),
+            hex!("00000000000000000000000000000000ffffffffffffffffffffffffffffffff").into()
+        );
+        assert_eq!(U256::from_u64(1000).div(&U256::from_u64(7)), U256::from_u64(142));
+        assert_eq!(dividend.div(&dividend), U256::from_u64(1));
+        assert!(dividend.checked_add(&U256::from_u64(1)).is_none());
+    }
+}